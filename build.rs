@@ -10,7 +10,7 @@ const APP_NAME: &str = "swww";
 
 fn main() -> Result<(), Error> {
     let outdir = completion_dir()?;
-    let mut app = Swww::command();
+    let mut app = Cli::command();
 
     let shells = [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::Elvish];
     for shell in shells {
@@ -1,24 +1,29 @@
-use log::error;
+use log::{debug, error, warn};
 use rkyv::{boxed::ArchivedBox, string::ArchivedString, Deserialize};
 
 use std::{
+    path::Path,
     sync::Arc,
     thread::{self, Scope},
     time::Duration,
 };
 
 use utils::{
+    cache::{self, CachedImg},
     compression::Decompressor,
+    imgproc,
     ipc::{
-        Answer, ArchivedAnimation, ArchivedImg, ArchivedRequest, ArchivedTransition, BgImg, Request,
+        AnimationPlayback, Answer, ArchivedAnimation, ArchivedAnimationPlayback, ArchivedImg,
+        ArchivedRequest, ArchivedTransition, BgImg, Coord, Img, Position, Request, TransitionType,
+        ValidRequest,
     },
 };
 
-use crate::wallpaper::{AnimationToken, Wallpaper};
+use crate::wallpaper::{AnimationToken, Playback, Wallpaper};
 
 mod anim_barrier;
 mod transitions;
-use transitions::Transition;
+use transitions::{Clock, Transition};
 
 use self::anim_barrier::ArcAnimBarrier;
 
@@ -27,12 +32,14 @@ const STACK_SIZE: usize = 1 << 17; //128KiB
 
 pub(super) struct Animator {
     anim_barrier: ArcAnimBarrier,
+    hidden_timeout: Option<Duration>,
 }
 
 impl Animator {
     pub(super) fn new() -> Self {
         Self {
             anim_barrier: ArcAnimBarrier::new(),
+            hidden_timeout: hidden_timeout(),
         }
     }
 
@@ -58,7 +65,8 @@ impl Animator {
                 let dimensions = wallpapers[0].get_dimensions();
 
                 if img.len() == dimensions.0 as usize * dimensions.1 as usize * 3 {
-                    Transition::new(wallpapers, dimensions, transition.clone()).execute(img);
+                    Transition::new(wallpapers, dimensions, transition.clone(), Clock::real())
+                        .execute(img);
                 } else {
                     error!(
                         "image is of wrong size! Image len: {}, expected size: {}",
@@ -74,33 +82,37 @@ impl Animator {
 
     pub(super) fn transition(
         &mut self,
-        bytes: Vec<u8>,
+        request: ValidRequest,
         wallpapers: Vec<Vec<Arc<Wallpaper>>>,
     ) -> Answer {
         match thread::Builder::new()
             .stack_size(1 << 15)
             .name("transition spawner".to_string())
-            .spawn(move || {
-                if let ArchivedRequest::Img((transition, imgs)) = Request::receive(&bytes) {
-                    thread::scope(|s| {
-                        for ((ArchivedImg { img, path }, _), wallpapers) in
-                            imgs.iter().zip(wallpapers)
-                        {
-                            Self::spawn_transition_thread(s, transition, img, path, wallpapers);
-                        }
-                    });
-                }
-            }) {
+            .spawn(move || Self::run_transitions(&request, wallpapers))
+        {
             Ok(_) => Answer::Ok,
             Err(e) => Answer::Err(e.to_string()),
         }
     }
 
+    /// Blocks until all transitions in `request` (which must be a `Request::Img`) are done
+    fn run_transitions(request: &ValidRequest, wallpapers: Vec<Vec<Arc<Wallpaper>>>) {
+        if let ArchivedRequest::Img((transition, imgs)) = request.get() {
+            thread::scope(|s| {
+                for ((ArchivedImg { img, path }, _), wallpapers) in imgs.iter().zip(wallpapers) {
+                    Self::spawn_transition_thread(s, transition, img, path, wallpapers);
+                }
+            });
+        }
+    }
+
     fn spawn_animation_thread<'a, 'b>(
         scope: &'a Scope<'b, '_>,
         animation: &'b ArchivedAnimation,
-        mut wallpapers: Vec<Arc<Wallpaper>>,
+        playback: &'b ArchivedAnimationPlayback,
+        wallpaper: Arc<Wallpaper>,
         barrier: ArcAnimBarrier,
+        hidden_timeout: Option<Duration>,
     ) where
         'a: 'b,
     {
@@ -114,61 +126,96 @@ impl Animator {
                 }
                 log::debug!("Starting animation");
 
-                let mut tokens: Vec<AnimationToken> = wallpapers
-                    .iter()
-                    .map(|w| w.create_animation_token())
-                    .collect();
-
-                for (wallpaper, token) in wallpapers.iter().zip(&tokens) {
-                    loop {
-                        if !wallpaper.has_animation_id(token) || token.is_transition_done() {
-                            break;
-                        }
-                        let duration: Duration = animation.animation[0]
-                            .1
-                            .deserialize(&mut rkyv::Infallible)
-                            .unwrap();
-                        std::thread::sleep(duration / 2);
+                let token = wallpaper.create_animation_token();
+                loop {
+                    if !wallpaper.has_animation_id(&token) || token.is_transition_done() {
+                        break;
                     }
+                    let duration: Duration = animation.animation[0]
+                        .1
+                        .deserialize(&mut rkyv::Infallible)
+                        .unwrap();
+                    std::thread::sleep(duration / 2);
                 }
 
+                token.set_animation_running(&wallpaper, true);
+
+                let speed = if AnimationPlayback::SPEEDS.contains(&playback.speed) {
+                    playback.speed
+                } else {
+                    1.0
+                };
+                // the first frame is already displayed when we start, so we stop right before the
+                // frame that would go back to it
+                let frame_count = match playback.loops {
+                    0 => None,
+                    loops => Some(
+                        (loops as usize * animation.animation.len())
+                            .saturating_sub(animation.wraps_around as usize),
+                    ),
+                };
+
                 let mut now = std::time::Instant::now();
 
                 let mut decompressor = Decompressor::new();
-                for (frame, duration) in animation.animation.iter().cycle() {
+                for (i, (frame, duration)) in animation.animation.iter().cycle().enumerate() {
+                    if frame_count.is_some_and(|count| i >= count) {
+                        debug!("animation in output {} finished", wallpaper.name());
+                        token.set_animation_running(&wallpaper, false);
+                        return;
+                    }
                     let duration: Duration = duration.deserialize(&mut rkyv::Infallible).unwrap();
-                    barrier.wait(duration.div_f32(2.0));
-
-                    let mut i = 0;
-                    while i < wallpapers.len() {
-                        let token = &tokens[i];
-                        if !wallpapers[i].has_animation_id(token) {
-                            wallpapers.swap_remove(i);
-                            tokens.swap_remove(i);
-                            continue;
+                    // frame durations come from the client, so they may be anything
+                    let duration = Duration::try_from_secs_f32(duration.as_secs_f32() / speed)
+                        .unwrap_or(duration);
+                    let stepping = match wallpaper.wait_playback(&token) {
+                        Playback::Stop => return,
+                        Playback::Play => false,
+                        Playback::Resume => {
+                            now = std::time::Instant::now();
+                            false
                         }
+                        Playback::Step => true,
+                    };
+                    if !stepping {
+                        barrier.wait(duration.div_f32(2.0));
+                    }
 
-                        let result = wallpapers[i].canvas_change(|canvas| {
-                            decompressor.decompress_archived(frame, canvas)
-                        });
-
-                        if let Err(e) = result {
-                            error!("failed to unpack frame: {e}");
-                            wallpapers.swap_remove(i);
-                            tokens.swap_remove(i);
-                            continue;
+                    if let Some(timeout) = hidden_timeout {
+                        if !wallpaper.wait_frame_callback(timeout) {
+                            debug!(
+                                "output {} seems hidden, pausing animation",
+                                wallpaper.name()
+                            );
+                            while !wallpaper.wait_frame_callback(timeout) {
+                                if !wallpaper.has_animation_id(&token) {
+                                    return;
+                                }
+                            }
+                            debug!("output {} is visible again", wallpaper.name());
+                            now = std::time::Instant::now();
                         }
+                    }
 
-                        wallpapers[i].draw();
-                        i += 1;
+                    if !wallpaper.has_animation_id(&token) {
+                        return;
                     }
 
-                    if wallpapers.is_empty() {
+                    let result = wallpaper
+                        .canvas_change(|canvas| decompressor.decompress_archived(frame, canvas));
+
+                    if let Err(e) = result {
+                        error!("failed to unpack frame: {e}");
+                        token.set_animation_running(&wallpaper, false);
                         return;
                     }
 
-                    let timeout = duration.saturating_sub(now.elapsed());
-                    spin_sleep::sleep(timeout);
+                    wallpaper.draw();
+
+                    if !stepping {
+                        let timeout = duration.saturating_sub(now.elapsed());
+                        spin_sleep::sleep(timeout);
+                    }
                     crate::wake_poll();
                     now = std::time::Instant::now();
                 }
@@ -180,25 +227,362 @@ impl Animator {
 
     pub(super) fn animate(
         &mut self,
-        bytes: Vec<u8>,
+        request: ValidRequest,
         wallpapers: Vec<Vec<Arc<Wallpaper>>>,
     ) -> Answer {
         let barrier = self.anim_barrier.clone();
+        let hidden_timeout = self.hidden_timeout;
         match thread::Builder::new()
             .stack_size(1 << 15)
             .name("animation spawner".to_string())
-            .spawn(move || {
-                thread::scope(|s| {
-                    if let ArchivedRequest::Animation(animations) = Request::receive(&bytes) {
-                        for ((animation, _), wallpapers) in animations.iter().zip(wallpapers) {
-                            let barrier = barrier.clone();
-                            Self::spawn_animation_thread(s, animation, wallpapers, barrier);
-                        }
-                    }
-                });
-            }) {
+            .spawn(move || Self::run_animations(&request, wallpapers, barrier, hidden_timeout))
+        {
             Ok(_) => Answer::Ok,
             Err(e) => Answer::Err(e.to_string()),
         }
     }
+
+    /// Blocks until all animations in `request` (which must be a `Request::Animation`) stop
+    fn run_animations(
+        request: &ValidRequest,
+        wallpapers: Vec<Vec<Arc<Wallpaper>>>,
+        barrier: ArcAnimBarrier,
+        hidden_timeout: Option<Duration>,
+    ) {
+        thread::scope(|s| {
+            if let ArchivedRequest::Animation((playback, animations)) = request.get() {
+                for ((animation, _), wallpapers) in animations.iter().zip(wallpapers) {
+                    // every wallpaper gets its own thread, so that they can be paused
+                    // independently
+                    for wallpaper in wallpapers {
+                        let barrier = barrier.clone();
+                        Self::spawn_animation_thread(
+                            s,
+                            animation,
+                            playback,
+                            wallpaper,
+                            barrier,
+                            hidden_timeout,
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    /// Displays the image last set in `wallpaper`'s output, as registered in the cache, together
+    /// with its animation if we have its frames cached
+    pub(super) fn restore(&mut self, wallpaper: Arc<Wallpaper>) {
+        let barrier = self.anim_barrier.clone();
+        let hidden_timeout = self.hidden_timeout;
+        // we take the token right away, so that if someone sets another image while we are
+        // loading this one, we give up
+        let token = wallpaper.create_animation_token();
+        if let Err(e) = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .name("cache loader".to_string())
+            .spawn(move || {
+                let cached = match cache::get_previous_image(wallpaper.name()) {
+                    Ok(Some(cached)) => cached,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("failed to load cache for output {}: {e}", wallpaper.name());
+                        return;
+                    }
+                };
+                let transition = instant_transition();
+                Self::show(
+                    wallpaper,
+                    token,
+                    &cached,
+                    transition,
+                    barrier,
+                    hidden_timeout,
+                );
+            })
+        {
+            error!("failed to spawn 'cache loader' thread: {e}");
+        }
+    }
+
+    /// Displays `img` with its own transition, like the client would. Used for playlists
+    pub(super) fn display(&mut self, wallpaper: Arc<Wallpaper>, img: CachedImg) {
+        let barrier = self.anim_barrier.clone();
+        let hidden_timeout = self.hidden_timeout;
+        // we take the token right away, so that any image set after this one takes precedence
+        let token = wallpaper.create_animation_token();
+        if let Err(e) = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .name("image loader".to_string())
+            .spawn(move || {
+                let transition = img.transition.clone().unwrap_or_else(instant_transition);
+                Self::show(wallpaper, token, &img, transition, barrier, hidden_timeout);
+            })
+        {
+            error!("failed to spawn 'image loader' thread: {e}");
+        }
+    }
+
+    /// Blocks until `img` is displayed in `wallpaper`, and then until its animation (if we have
+    /// its frames cached) stops. We give up if someone sets another image before `token` was
+    /// taken
+    fn show(
+        wallpaper: Arc<Wallpaper>,
+        token: AnimationToken,
+        img: &CachedImg,
+        transition: utils::ipc::Transition,
+        barrier: ArcAnimBarrier,
+        hidden_timeout: Option<Duration>,
+    ) {
+        let (img, animation) = match make_requests(&wallpaper, img, transition) {
+            Ok(requests) => requests,
+            Err(e) => {
+                warn!(
+                    "failed to load {} for output {}: {e}",
+                    img.path,
+                    wallpaper.name()
+                );
+                return;
+            }
+        };
+        if !wallpaper.has_animation_id(&token) {
+            return;
+        }
+        wallpaper.stop_animations();
+        Self::run_transitions(&img, vec![vec![Arc::clone(&wallpaper)]]);
+        if let Some(animation) = animation {
+            let wallpapers = vec![vec![wallpaper]];
+            Self::run_animations(&animation, wallpapers, barrier, hidden_timeout);
+        }
+    }
+}
+
+/// When set, animations pause if the compositor does not ask for a new frame within this many
+/// milliseconds, since that usually means their output is not visible. Configurable through the
+/// `SWWW_HIDDEN_TIMEOUT` environment variable
+fn hidden_timeout() -> Option<Duration> {
+    let timeout = std::env::var("SWWW_HIDDEN_TIMEOUT").ok()?;
+    match timeout.parse::<u64>() {
+        Ok(0) => None,
+        Ok(ms) => Some(Duration::from_millis(ms)),
+        Err(e) => {
+            warn!("ignoring invalid SWWW_HIDDEN_TIMEOUT ({timeout}): {e}");
+            None
+        }
+    }
+}
+
+fn instant_transition() -> utils::ipc::Transition {
+    utils::ipc::Transition {
+        transition_type: TransitionType::Simple,
+        duration: 0.0,
+        step: u8::MAX,
+        fps: 30,
+        angle: 0.0,
+        pos: Position::new(Coord::Pixel(0.0), Coord::Pixel(0.0)),
+        bezier: (0.0, 0.0, 0.0, 0.0),
+        wave: (0.0, 0.0),
+        invert_y: false,
+    }
+}
+
+/// Builds the requests that would display `cached` in `wallpaper`. We serialize them just like a
+/// client would, so that they can go through the usual transition and animation code
+fn make_requests(
+    wallpaper: &Wallpaper,
+    cached: &CachedImg,
+    transition: utils::ipc::Transition,
+) -> Result<(ValidRequest, Option<ValidRequest>), String> {
+    let dimensions = wallpaper.get_dimensions();
+    let outputs: Box<[String]> = Box::new([wallpaper.name().to_string()]);
+
+    let img = imgproc::decode(Path::new(&cached.path))?;
+    let img = match &cached.span {
+        Some(span) => imgproc::resize_span(
+            img,
+            span,
+            dimensions,
+            cached.resize,
+            cached.filter,
+            &cached.fill_color,
+        )?,
+        None => imgproc::resize(
+            img,
+            dimensions,
+            cached.resize,
+            cached.filter,
+            &cached.fill_color,
+        )?,
+    };
+    let request = Request::Img((
+        transition,
+        Box::new([(
+            Img {
+                path: cached.path.clone(),
+                img: img.into_boxed_slice(),
+            },
+            outputs.clone(),
+        )]),
+    ));
+    let img = rkyv::to_bytes::<_, 1024>(&request)
+        .map_err(|e| format!("failed to serialize image: {e}"))?;
+    let img = ValidRequest::new(img).map_err(|e| e.to_string())?;
+
+    // spanned images only display their first frame
+    if cached.span.is_some() {
+        return Ok((img, None));
+    }
+    let animation = match cache::load_animation_frames(
+        Path::new(&cached.path),
+        dimensions,
+        cached.resize,
+        cached.filter,
+        cached.fill_color,
+        cached.order,
+    ) {
+        Ok(Some(animation)) => {
+            let playback = AnimationPlayback {
+                speed: cached.speed,
+                loops: cached.loops,
+            };
+            let request = Request::Animation((playback, Box::new([(animation, outputs)])));
+            let animation = rkyv::to_bytes::<_, 1024>(&request)
+                .map_err(|e| format!("failed to serialize animation: {e}"))?;
+            Some(ValidRequest::new(animation).map_err(|e| e.to_string())?)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("failed to load cached animation frames: {e}");
+            None
+        }
+    };
+    Ok((img, animation))
+}
+
+/// Runs `transition` from `from` to `to` on a wallpaper of `dimensions` that draws to memory,
+/// returning every frame it drew, the first one being `from`. The clock only moves between frames,
+/// so it doesn't matter how long they take to draw
+pub(super) fn preview(
+    transition: &ArchivedTransition,
+    dimensions: (u32, u32),
+    from: &[u8],
+    to: &[u8],
+) -> Vec<Box<[u8]>> {
+    let (wallpaper, frames) = Wallpaper::headless("preview", dimensions);
+    wallpaper.canvas_change(|canvas| {
+        for (pixel, from) in canvas.chunks_exact_mut(4).zip(from.chunks_exact(3)) {
+            pixel[..3].copy_from_slice(from);
+        }
+    });
+    wallpaper.draw();
+    Transition::new(
+        vec![Arc::new(wallpaper)],
+        dimensions,
+        transition.clone(),
+        Clock::Fixed(Duration::ZERO),
+    )
+    .execute(to);
+    frames.take()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render_target::Frames;
+    use utils::{compression::Compressor, ipc::Animation};
+
+    const SIZE: (u32, u32) = (8, 4);
+
+    fn headless() -> (Arc<Wallpaper>, Frames) {
+        let (wallpaper, frames) = Wallpaper::headless("test", SIZE);
+        (Arc::new(wallpaper), frames)
+    }
+
+    /// An image of a single color, in the format the client sends them
+    fn solid(color: [u8; 3]) -> Vec<u8> {
+        color.repeat((SIZE.0 * SIZE.1) as usize)
+    }
+
+    /// Drops the padding byte of every pixel in `frame`, so it can be compared to an image
+    fn pixels(frame: &[u8]) -> Vec<u8> {
+        frame
+            .chunks_exact(4)
+            .flat_map(|pixel| &pixel[..3])
+            .copied()
+            .collect()
+    }
+
+    fn show(wallpaper: &Arc<Wallpaper>, img: Vec<u8>, transition: utils::ipc::Transition) {
+        let img = Img {
+            path: "test".to_string(),
+            img: img.into_boxed_slice(),
+        };
+        let request = Request::Img((transition, Box::new([(img, Box::new([]) as Box<[_]>)])));
+        let bytes = rkyv::to_bytes::<_, 1024>(&request).unwrap();
+        let request = ValidRequest::new(bytes).unwrap();
+        Animator::run_transitions(&request, vec![vec![Arc::clone(wallpaper)]]);
+    }
+
+    #[test]
+    fn transitions_end_on_the_new_image() {
+        let (wallpaper, frames) = headless();
+        let transition = utils::ipc::Transition {
+            step: 100,
+            fps: 1000,
+            ..instant_transition()
+        };
+        show(&wallpaper, solid([255, 128, 0]), transition);
+
+        let frames = frames.take();
+        // 255 takes three steps of 100
+        assert_eq!(frames.len(), 3);
+        assert_eq!(pixels(&frames[0]), solid([100, 100, 0]));
+        assert_eq!(pixels(&frames[2]), solid([255, 128, 0]));
+        assert!(matches!(wallpaper.get_img_info(), BgImg::Img(path) if path == "test"));
+        assert!(!wallpaper.is_transition_running());
+    }
+
+    #[test]
+    fn animations_play_every_frame() {
+        let (wallpaper, frames) = headless();
+        let imgs = [
+            solid([10, 10, 10]),
+            solid([20, 20, 20]),
+            solid([30, 30, 30]),
+        ];
+        show(&wallpaper, imgs[0].clone(), instant_transition());
+        assert_eq!(pixels(&frames.take()[0]), imgs[0]);
+
+        let mut compressor = Compressor::new();
+        let frame_time = Duration::from_millis(1);
+        let animation = Animation {
+            animation: (0..imgs.len())
+                .map(|i| {
+                    let next = &imgs[(i + 1) % imgs.len()];
+                    (compressor.compress(&imgs[i], next).unwrap(), frame_time)
+                })
+                .collect(),
+            path: "test".to_string(),
+            dimensions: SIZE,
+            wraps_around: true,
+        };
+        // playing it once stops on the last frame, without going back to the first
+        let playback = AnimationPlayback {
+            speed: 1.0,
+            loops: 1,
+        };
+        let request = Request::Animation((playback, Box::new([(animation, Box::new([]) as _)])));
+        let bytes = rkyv::to_bytes::<_, 1024>(&request).unwrap();
+        let request = ValidRequest::new(bytes).unwrap();
+        Animator::run_animations(
+            &request,
+            vec![vec![Arc::clone(&wallpaper)]],
+            ArcAnimBarrier::new(),
+            None,
+        );
+
+        let frames: Vec<_> = frames.take().iter().map(|frame| pixels(frame)).collect();
+        assert_eq!(frames, &imgs[1..]);
+        assert!(!wallpaper.is_animation_running());
+    }
 }
@@ -42,6 +42,40 @@ macro_rules! change_cols {
     };
 }
 
+/// Where transitions get the time from
+pub(super) enum Clock {
+    /// The wall clock, counting from when we made it
+    Real(Instant),
+    /// A clock that only moves when we sleep, by exactly as long as we slept. Frames then only
+    /// depend on the transition's settings, and not on how long they took to draw, so they are
+    /// reproducible
+    Fixed(Duration),
+}
+
+impl Clock {
+    pub(super) fn real() -> Self {
+        Self::Real(Instant::now())
+    }
+
+    fn now(&self) -> Duration {
+        match self {
+            Self::Real(start) => start.elapsed(),
+            Self::Fixed(now) => *now,
+        }
+    }
+
+    fn since(&self, earlier: Duration) -> Duration {
+        self.now().saturating_sub(earlier)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        match self {
+            Self::Real(_) => spin_sleep::sleep(duration),
+            Self::Fixed(now) => *now += duration,
+        }
+    }
+}
+
 pub(super) struct Transition {
     animation_tokens: Vec<AnimationToken>,
     wallpapers: Vec<Arc<Wallpaper>>,
@@ -55,6 +89,7 @@ pub(super) struct Transition {
     bezier: BezierCurve,
     wave: (f32, f32),
     invert_y: bool,
+    clock: Clock,
 }
 
 /// All transitions return whether or not they completed
@@ -63,6 +98,7 @@ impl Transition {
         wallpapers: Vec<Arc<Wallpaper>>,
         dimensions: (u32, u32),
         transition: utils::ipc::ArchivedTransition,
+        clock: Clock,
     ) -> Self {
         Transition {
             animation_tokens: wallpapers
@@ -89,11 +125,15 @@ impl Transition {
             ),
             wave: transition.wave,
             invert_y: transition.invert_y,
+            clock,
         }
     }
 
     pub(super) fn execute(mut self, new_img: &[u8]) {
         debug!("Starting transitions");
+        for (wallpaper, token) in self.wallpapers.iter().zip(&self.animation_tokens) {
+            token.set_transition_running(wallpaper);
+        }
         match self.transition_type {
             ArchivedTransitionType::Simple => self.simple(new_img),
             ArchivedTransitionType::Wipe => self.wipe(new_img),
@@ -108,7 +148,7 @@ impl Transition {
         }
     }
 
-    fn send_frame(&mut self, now: &mut Instant) {
+    fn send_frame(&mut self, now: &mut Duration) {
         let fps = self.fps;
         let mut i = 0;
         while i < self.wallpapers.len() {
@@ -120,22 +160,22 @@ impl Transition {
             }
             i += 1;
         }
-        let timeout = fps.saturating_sub(now.elapsed());
-        spin_sleep::sleep(timeout);
+        let timeout = fps.saturating_sub(self.clock.since(*now));
+        self.clock.sleep(timeout);
         crate::wake_poll();
-        *now = Instant::now();
+        *now = self.clock.now();
     }
 
-    fn bezier_seq(&self, start: f32, end: f32) -> (AnimationSequence<f32>, Instant) {
+    fn bezier_seq(&self, start: f32, end: f32) -> (AnimationSequence<f32>, Duration) {
         (
             keyframes![(start, 0.0, self.bezier), (end, self.duration, self.bezier)],
-            Instant::now(),
+            self.clock.now(),
         )
     }
 
     fn simple(&mut self, new_img: &[u8]) {
         let step = self.step;
-        let mut now = Instant::now();
+        let mut now = self.clock.now();
         let mut done = false;
         while !done {
             done = true;
@@ -155,8 +195,8 @@ impl Transition {
         let mut step = 0.0;
         let (mut seq, start) = self.bezier_seq(0.0, 1.0);
 
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
+        let mut now = self.clock.now();
+        while self.clock.since(start).as_secs_f64() < seq.duration() {
             for wallpaper in self.wallpapers.iter_mut() {
                 wallpaper.canvas_change(|canvas| {
                     canvas
@@ -173,7 +213,7 @@ impl Transition {
             }
             self.send_frame(&mut now);
             step = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
+            seq.advance_to(self.clock.since(start).as_secs_f64());
         }
         self.step = 4 + self.step / 4;
         self.simple(new_img)
@@ -182,7 +222,7 @@ impl Transition {
     fn wave(&mut self, new_img: &[u8]) {
         let width = self.dimensions.0;
         let height = self.dimensions.1;
-        let mut now = Instant::now();
+        let mut now = self.clock.now();
         let center = (width / 2, height / 2);
         let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
 
@@ -225,7 +265,7 @@ impl Transition {
 
         let step = self.step;
 
-        while start.elapsed().as_secs_f64() < seq.duration() {
+        while self.clock.since(start).as_secs_f64() < seq.duration() {
             for wallpaper in self.wallpapers.iter_mut() {
                 wallpaper.canvas_change(|canvas| {
                     canvas
@@ -245,7 +285,7 @@ impl Transition {
             self.send_frame(&mut now);
 
             offset = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
+            seq.advance_to(self.clock.since(start).as_secs_f64());
         }
         self.step = 4 + self.step / 4;
         self.simple(new_img)
@@ -254,7 +294,7 @@ impl Transition {
     fn wipe(&mut self, new_img: &[u8]) {
         let width = self.dimensions.0;
         let height = self.dimensions.1;
-        let mut now = Instant::now();
+        let mut now = self.clock.now();
         let center = (width / 2, height / 2);
         let screen_diag = ((width.pow(2) + height.pow(2)) as f64).sqrt();
 
@@ -286,7 +326,7 @@ impl Transition {
 
         let step = self.step;
 
-        while start.elapsed().as_secs_f64() < seq.duration() {
+        while self.clock.since(start).as_secs_f64() < seq.duration() {
             for wallpaper in self.wallpapers.iter_mut() {
                 wallpaper.canvas_change(|canvas| {
                     canvas
@@ -306,7 +346,7 @@ impl Transition {
             self.send_frame(&mut now);
 
             offset = seq.now() as f64;
-            seq.advance_to(start.elapsed().as_secs_f64());
+            seq.advance_to(self.clock.since(start).as_secs_f64());
         }
         self.step = 4 + self.step / 4;
         self.simple(new_img)
@@ -332,8 +372,8 @@ impl Transition {
         let (center_x, center_y) = (center_x as usize, center_y as usize);
 
         let (mut seq, start) = self.bezier_seq(0.0, dist_end);
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
+        let mut now = self.clock.now();
+        while self.clock.since(start).as_secs_f64() < seq.duration() {
             for wallpaper in self.wallpapers.iter_mut() {
                 wallpaper.canvas_change(|canvas| {
                     canvas
@@ -359,7 +399,7 @@ impl Transition {
             self.send_frame(&mut now);
 
             dist_center = seq.now();
-            seq.advance_to(start.elapsed().as_secs_f64());
+            seq.advance_to(self.clock.since(start).as_secs_f64());
         }
         self.step = 4 + self.step / 4;
         self.simple(new_img)
@@ -383,8 +423,8 @@ impl Transition {
         let (center_x, center_y) = (center_x as usize, center_y as usize);
 
         let (mut seq, start) = self.bezier_seq(dist_center, 0.0);
-        let mut now = Instant::now();
-        while start.elapsed().as_secs_f64() < seq.duration() {
+        let mut now = self.clock.now();
+        while self.clock.since(start).as_secs_f64() < seq.duration() {
             for wallpaper in self.wallpapers.iter_mut() {
                 wallpaper.canvas_change(|canvas| {
                     canvas
@@ -410,9 +450,177 @@ impl Transition {
             self.send_frame(&mut now);
 
             dist_center = seq.now();
-            seq.advance_to(start.elapsed().as_secs_f64());
+            seq.advance_to(self.clock.since(start).as_secs_f64());
         }
         self.step = 4 + self.step / 4;
         self.simple(new_img)
     }
 }
+
+/// Golden-image tests: we run every transition on a fixed clock, and compare some of its frames
+/// against the reference images in `daemon/golden`. After changing how a transition looks on
+/// purpose, run them with `SWWW_BLESS=1` to make new references
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use utils::ipc::{self, Coord, Position, TransitionType};
+
+    const SIZE: (u32, u32) = (64, 48);
+    /// How much a color channel may be off from the reference, so that tiny differences in
+    /// floating point math don't fail the tests
+    const TOLERANCE: u8 = 8;
+    /// The fraction of the channels that may be more than `TOLERANCE` off, for the same reason
+    const MAX_DIFFERENT: f64 = 0.01;
+
+    /// Makes an image in the format the client sends them
+    fn img(f: impl Fn(u32, u32) -> [u8; 3]) -> Vec<u8> {
+        let f = &f;
+        (0..SIZE.1)
+            .flat_map(|y| (0..SIZE.0).flat_map(move |x| f(x, y)))
+            .collect()
+    }
+
+    fn old_img() -> Vec<u8> {
+        img(|x, y| [(x * 4) as u8, (y * 5) as u8, 128])
+    }
+
+    fn new_img() -> Vec<u8> {
+        img(|x, y| {
+            if (x / 8 + y / 8) % 2 == 0 {
+                [240, 40, 90]
+            } else {
+                [20, 200, 160]
+            }
+        })
+    }
+
+    /// The frames of a transition from `old_img` to `new_img`, with swww's default settings
+    fn run(transition_type: TransitionType) -> Vec<Box<[u8]>> {
+        let (wallpaper, frames) = Wallpaper::headless("golden", SIZE);
+        let old = old_img();
+        wallpaper.canvas_change(|canvas| {
+            for (pixel, old) in canvas.chunks_exact_mut(4).zip(old.chunks_exact(3)) {
+                pixel[..3].copy_from_slice(old);
+            }
+        });
+
+        let step = match transition_type {
+            TransitionType::Simple => 2,
+            _ => 90,
+        };
+        let transition = ipc::Transition {
+            transition_type,
+            duration: 1.0,
+            step,
+            fps: 30,
+            angle: 45.0,
+            pos: Position::new(Coord::Percent(0.5), Coord::Percent(0.5)),
+            bezier: (0.54, 0.0, 0.34, 0.99),
+            wave: (20.0, 20.0),
+            invert_y: false,
+        };
+        let bytes = rkyv::to_bytes::<_, 256>(&transition).unwrap();
+        let transition = rkyv::check_archived_root::<ipc::Transition>(&bytes).unwrap();
+        Transition::new(
+            vec![Arc::new(wallpaper)],
+            SIZE,
+            transition.clone(),
+            Clock::Fixed(Duration::ZERO),
+        )
+        .execute(&new_img());
+        frames.take()
+    }
+
+    /// Drops the padding byte of every pixel in `frame`, and turns it into RGB
+    fn rgb(frame: &[u8]) -> Vec<u8> {
+        frame
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+            .collect()
+    }
+
+    fn golden(name: &str, transition_type: TransitionType) {
+        let frames = run(transition_type);
+        let new: Vec<u8> = new_img()
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+            .collect();
+        assert_eq!(
+            rgb(frames.last().unwrap()),
+            new,
+            "{name} did not end on the new image"
+        );
+
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("golden");
+        let bless = std::env::var_os("SWWW_BLESS").is_some();
+        if bless {
+            for entry in dir.read_dir().unwrap().flatten() {
+                if entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&format!("{name}_"))
+                {
+                    std::fs::remove_file(entry.path()).unwrap();
+                }
+            }
+        }
+
+        // a different number of frames means the timing changed, and shows up as missing files
+        let len = frames.len();
+        for i in [len / 4, len / 2, len * 3 / 4] {
+            let path = dir.join(format!("{name}_{i:03}.png"));
+            let frame = rgb(&frames[i]);
+            if bless {
+                image::save_buffer(&path, &frame, SIZE.0, SIZE.1, image::ColorType::Rgb8).unwrap();
+                continue;
+            }
+            let reference = match image::open(&path) {
+                Ok(reference) => reference.into_rgb8().into_raw(),
+                Err(e) => panic!(
+                    "failed to open {path:?} ({e}). The {name} transition took {len} frames. If \
+                     that is expected, run the tests with SWWW_BLESS=1 to make new references"
+                ),
+            };
+            let different = reference
+                .iter()
+                .zip(&frame)
+                .filter(|(a, b)| a.abs_diff(**b) > TOLERANCE)
+                .count();
+            assert!(
+                different as f64 <= MAX_DIFFERENT * frame.len() as f64,
+                "frame {i} of the {name} transition differs from {path:?} in {different} channels"
+            );
+        }
+    }
+
+    #[test]
+    fn simple() {
+        golden("simple", TransitionType::Simple);
+    }
+
+    #[test]
+    fn fade() {
+        golden("fade", TransitionType::Fade);
+    }
+
+    #[test]
+    fn wipe() {
+        golden("wipe", TransitionType::Wipe);
+    }
+
+    #[test]
+    fn wave() {
+        golden("wave", TransitionType::Wave);
+    }
+
+    #[test]
+    fn grow() {
+        golden("grow", TransitionType::Grow);
+    }
+
+    #[test]
+    fn outer() {
+        golden("outer", TransitionType::Outer);
+    }
+}
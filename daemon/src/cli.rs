@@ -0,0 +1,82 @@
+//! The daemon's command line. `swww init` passes its own options through it, so there is usually
+//! no need to use it directly
+
+use clap::{Parser, ValueEnum};
+use log::LevelFilter;
+use smithay_client_toolkit::shell::wlr_layer::Layer;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "The daemon that displays the wallpapers sent by swww"
+)]
+pub(super) struct Cli {
+    /// Which layer-shell layer to draw the wallpapers on.
+    ///
+    /// Use `bottom` with compositors that draw something of their own on the background layer.
+    #[arg(long, default_value = "background")]
+    pub layer: CliLayer,
+
+    /// The layer-shell namespace of our surfaces, which compositors can match in their rules
+    #[arg(long, default_value = "swww")]
+    pub layer_namespace: String,
+
+    /// Which namespace to run in. Each one has its own socket and cache, so there can be a
+    /// daemon per namespace
+    #[arg(long, env = "SWWW_SOCKET")]
+    pub namespace: Option<String>,
+
+    /// The most detailed kind of message to log. Release builds never log debug messages
+    #[arg(long, default_value = "debug")]
+    pub log_level: LogLevel,
+
+    /// Append the logs to this file, instead of printing them to stderr
+    #[arg(long, conflicts_with = "syslog")]
+    pub log_file: Option<PathBuf>,
+
+    /// Send the logs to the system log (`/dev/log`), which journald also reads
+    #[arg(long)]
+    pub syslog: bool,
+
+    /// Render the transition `swww preview` writes to our stdin, and write its frames to stdout,
+    /// instead of running as a daemon
+    #[arg(long, hide = true)]
+    pub preview: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(super) enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(super) enum CliLayer {
+    Background,
+    Bottom,
+}
+
+impl From<CliLayer> for Layer {
+    fn from(layer: CliLayer) -> Self {
+        match layer {
+            CliLayer::Background => Layer::Background,
+            CliLayer::Bottom => Layer::Bottom,
+        }
+    }
+}
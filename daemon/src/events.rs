@@ -0,0 +1,96 @@
+//! Streams [`Event`]s to every client that subscribed to them
+//!
+//! Events may be emitted from any thread (transitions and animations run in their own), so the
+//! subscribers live in a global, just like the poll waker in `main.rs`
+
+use log::{debug, error};
+use std::{io::Write, os::unix::net::UnixStream, sync::Mutex};
+
+use utils::ipc::Event;
+
+static SUBSCRIBERS: Subscribers = Subscribers::new();
+
+/// Keeps `stream` open, sending it every event from now on
+pub(super) fn subscribe(stream: UnixStream) {
+    SUBSCRIBERS.subscribe(stream);
+}
+
+/// Sends `event` to all subscribers
+pub(super) fn emit(event: Event) {
+    SUBSCRIBERS.emit(&event);
+}
+
+struct Subscribers(Mutex<Vec<UnixStream>>);
+
+impl Subscribers {
+    const fn new() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+
+    fn subscribe(&self, stream: UnixStream) {
+        // We must never block whoever is emitting the event (often the main loop), so slow
+        // subscribers are simply dropped, below
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("failed to set subscriber to nonblocking mode: {e}");
+            return;
+        }
+        self.0.lock().unwrap().push(stream);
+        debug!("new event subscriber");
+    }
+
+    fn emit(&self, event: &Event) {
+        let mut subscribers = self.0.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let bytes = match event.to_bytes() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("{e}");
+                return;
+            }
+        };
+        // Note a partial write would corrupt the stream, so we drop the subscriber on any error
+        subscribers.retain(|mut stream| match stream.write_all(&bytes) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("dropping event subscriber: {e}");
+                false
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::ipc::{read_socket, ArchivedEvent};
+
+    #[test]
+    fn subscribers_receive_events() {
+        let subscribers = Subscribers::new();
+        let (client, daemon) = UnixStream::pair().unwrap();
+        subscribers.subscribe(daemon);
+        subscribers.emit(&Event::AnimationStarted {
+            output: "DP-1".to_string(),
+        });
+
+        let bytes = read_socket(&client).unwrap();
+        match Event::receive(&bytes).unwrap() {
+            ArchivedEvent::AnimationStarted { output } => assert_eq!(output.as_str(), "DP-1"),
+            _ => panic!("received the wrong event"),
+        }
+    }
+
+    #[test]
+    fn closed_subscribers_are_dropped() {
+        let subscribers = Subscribers::new();
+        let (client, daemon) = UnixStream::pair().unwrap();
+        subscribers.subscribe(daemon);
+        drop(client);
+        subscribers.emit(&Event::OutputRemoved {
+            output: "DP-1".to_string(),
+        });
+        assert!(subscribers.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+//! Sets up where the daemon's logs go: stderr (the default), a file, or the system log
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode, WriteLogger};
+use std::{
+    fs::OpenOptions,
+    io::IsTerminal,
+    os::unix::net::UnixDatagram,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::cli::Cli;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+pub(super) fn init(cli: &Cli) -> Result<(), String> {
+    let level = LevelFilter::from(cli.log_level);
+    let config = simplelog::ConfigBuilder::new()
+        .set_thread_level(LevelFilter::Error) // let me see where the processing is happening
+        .set_thread_mode(ThreadLogMode::Both)
+        .build();
+
+    let result = if cli.syslog {
+        let logger = SysLogger::connect()?;
+        log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(level))
+    } else if let Some(path) = &cli.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("failed to open log file {path:?}: {e}"))?;
+        WriteLogger::init(level, config, file)
+    } else {
+        // colours would only garble logs that don't end up in a terminal
+        let color = if std::io::stderr().is_terminal() {
+            ColorChoice::AlwaysAnsi
+        } else {
+            ColorChoice::Never
+        };
+        TermLogger::init(level, config, TerminalMode::Stderr, color)
+    };
+    result.map_err(|e| format!("failed to initialize logger: {e}"))
+}
+
+/// Sends each message as a datagram to the syslog socket, which journald also listens on
+struct SysLogger {
+    socket: Mutex<UnixDatagram>,
+    pid: u32,
+}
+
+impl SysLogger {
+    fn connect() -> Result<Self, String> {
+        let socket =
+            UnixDatagram::unbound().map_err(|e| format!("failed to create syslog socket: {e}"))?;
+        socket
+            .connect(SYSLOG_SOCKET)
+            .map_err(|e| format!("failed to connect to syslog at {SYSLOG_SOCKET}: {e}"))?;
+        Ok(Self {
+            socket: Mutex::new(socket),
+            pid: std::process::id(),
+        })
+    }
+}
+
+/// The syslog priority of a message: the `user` facility, and the severity matching `level`
+fn priority(level: Level) -> u8 {
+    const USER: u8 = 1 << 3;
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    USER | severity
+}
+
+impl Log for SysLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let thread = std::thread::current();
+        let msg = format!(
+            "<{}>swww-daemon[{}]: ({}) {}",
+            priority(record.level()),
+            self.pid,
+            thread.name().unwrap_or("unnamed"),
+            record.args()
+        );
+        let socket = self.socket.lock().unwrap_or_else(PoisonError::into_inner);
+        // there is nowhere left to report a failure to log
+        let _ = socket.send(msg.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syslog_priorities() {
+        assert_eq!(priority(Level::Error), 11);
+        assert_eq!(priority(Level::Warn), 12);
+        assert_eq!(priority(Level::Info), 14);
+        assert_eq!(priority(Level::Debug), 15);
+    }
+}
@@ -4,27 +4,36 @@
 
 mod animations;
 pub mod bump_pool;
+mod cli;
+mod events;
+mod logger;
+mod playlist;
+mod preview;
+mod render_target;
+mod schedule;
 mod wallpaper;
-use log::{debug, error, info, warn, LevelFilter};
+use clap::Parser;
+use log::{debug, error, info, warn};
 use nix::{
     poll::{poll, PollFd, PollFlags},
     sys::signal::{self, SigHandler, Signal},
 };
-use rkyv::{boxed::ArchivedBox, string::ArchivedString};
-use simplelog::{ColorChoice, TermLogger, TerminalMode, ThreadLogMode};
-use wallpaper::Wallpaper;
+use render_target::Viewport;
+use rkyv::{boxed::ArchivedBox, string::ArchivedString, Deserialize};
+use wallpaper::{Restore, Wallpaper};
 
 use std::{
     fs,
     num::NonZeroI32,
     os::{
-        fd::{BorrowedFd, RawFd},
+        fd::{BorrowedFd, FromRawFd, RawFd},
         unix::net::{UnixListener, UnixStream},
     },
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, OnceLock,
     },
+    time::{Duration, Instant},
 };
 
 use smithay_client_toolkit::{
@@ -40,15 +49,30 @@ use smithay_client_toolkit::{
     shm::{Shm, ShmHandler},
 };
 
+use smithay_client_toolkit::reexports::protocols::wp::{
+    fractional_scale::v1::client::{
+        wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1,
+        wp_fractional_scale_v1::{self, WpFractionalScaleV1},
+    },
+    viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+};
 use wayland_client::{
     globals::{registry_queue_init, GlobalList},
     protocol::{wl_buffer::WlBuffer, wl_output, wl_surface},
     Connection, Dispatch, QueueHandle,
 };
 
-use utils::ipc::{get_socket_path, Answer, ArchivedRequest, BgInfo, Request};
+use utils::{
+    cache::{self, CachedImg, CachedPlaylist},
+    ipc::{
+        self, daemon_handshake, get_socket_path, max_message_size, read_message, Answer,
+        ArchivedRequest, BgInfo, Event, MessageError, ValidRequest,
+    },
+};
 
 use animations::Animator;
+use playlist::Playlists;
+use schedule::Scheduler;
 
 // We need this because this might be set by signals, so we can't keep it in the daemon
 static EXIT: AtomicBool = AtomicBool::new(false);
@@ -66,8 +90,11 @@ fn should_daemon_exit() -> bool {
 static POLL_WAKER: OnceLock<RawFd> = OnceLock::new();
 
 pub fn wake_poll() {
-    if let Err(e) = nix::unistd::write(*unsafe { POLL_WAKER.get().unwrap_unchecked() }, &[0]) {
-        error!("failed to write to pipe file descriptor: {e}");
+    // there is no pipe when we draw without a compositor
+    if let Some(waker) = POLL_WAKER.get() {
+        if let Err(e) = nix::unistd::write(*waker, &[0]) {
+            error!("failed to write to pipe file descriptor: {e}");
+        }
     }
 }
 
@@ -80,7 +107,12 @@ fn main() -> Result<(), String> {
         .thread_name(|i| format!("rayon thread {i}"))
         .build_global()
         .expect("failed to configure rayon global thread pool");
-    make_logger();
+    let cli = cli::Cli::parse();
+    if cli.preview {
+        return preview::run();
+    }
+    ipc::set_namespace(cli.namespace.clone())?;
+    logger::init(&cli)?;
     let listener = SocketWrapper::new()?;
     let wake = setup_signals_and_pipe();
 
@@ -90,7 +122,7 @@ fn main() -> Result<(), String> {
         registry_queue_init(&conn).expect("failed to initialize the event queue");
     let qh = event_queue.handle();
 
-    let mut daemon = Daemon::new(&globals, &qh);
+    let mut daemon = Daemon::new(&globals, &qh, cli, listener.socket_activated);
 
     if let Ok(true) = sd_notify::booted() {
         if let Err(e) = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]) {
@@ -112,12 +144,25 @@ fn main() -> Result<(), String> {
             let connection_fd = read_guard.connection_fd();
             let waker = unsafe { BorrowedFd::borrow_raw(wake) };
             let mut fds = [
-                PollFd::new(&listener.0, PollFlags::POLLIN),
+                PollFd::new(&listener.listener, PollFlags::POLLIN),
                 PollFd::new(&connection_fd, PollFlags::POLLIN | PollFlags::POLLRDBAND),
                 PollFd::new(&waker, PollFlags::POLLIN),
             ];
 
-            match poll(&mut fds, -1) {
+            // wake up in time for the next playlist switch or schedule check
+            let timeout = [
+                daemon.playlists.timeout(),
+                daemon.scheduler.timeout(),
+                daemon.initialization.timeout(),
+            ];
+            let timeout = match timeout.into_iter().flatten().min() {
+                Some(timeout) => {
+                    let millis = (timeout.as_nanos() + 999_999) / 1_000_000;
+                    millis.min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+            match poll(&mut fds, timeout) {
                 Ok(_) => (),
                 Err(e) => match e {
                     nix::errno::Errno::EINTR => (),
@@ -139,7 +184,7 @@ fn main() -> Result<(), String> {
 
         if let Some(flags) = events[0] {
             if !flags.is_empty() {
-                match listener.0.accept() {
+                match listener.listener.accept() {
                     Ok((stream, _adr)) => daemon.recv_socket_msg(stream),
                     Err(e) => match e.kind() {
                         std::io::ErrorKind::WouldBlock => (),
@@ -156,6 +201,9 @@ fn main() -> Result<(), String> {
                 }
             }
         }
+
+        daemon.advance_playlists();
+        daemon.apply_schedule();
     }
 
     if let Err(e) = nix::unistd::close(*POLL_WAKER.get().unwrap()) {
@@ -169,6 +217,27 @@ fn main() -> Result<(), String> {
     Ok(())
 }
 
+/// Fills `wallpapers` with `color`, in another thread, since we may have to wait for their frame
+/// callbacks
+fn spawn_clear(wallpapers: Vec<Arc<Wallpaper>>, color: [u8; 3]) -> Result<(), String> {
+    std::thread::Builder::new()
+        .stack_size(1 << 15)
+        .name("clear".to_string())
+        .spawn(move || {
+            for wallpaper in &wallpapers {
+                wallpaper.stop_animations();
+            }
+            for wallpaper in wallpapers {
+                wallpaper.set_img_info(utils::ipc::BgImg::Color(color));
+                wallpaper.clear(color);
+                wallpaper.draw();
+            }
+            wake_poll();
+        })
+        .map(|_| ())
+        .map_err(|e| format!("failed to spawn `clear` thread: {e}"))
+}
+
 /// Returns the file descriptor we should install in the poll handler
 fn setup_signals_and_pipe() -> RawFd {
     let handler = SigHandler::Handler(signal_handler);
@@ -182,10 +251,78 @@ fn setup_signals_and_pipe() -> RawFd {
 
 /// This is a wrapper that makes sure to delete the socket when it is dropped
 /// It also makes sure to set the listener to nonblocking mode
-struct SocketWrapper(UnixListener);
+///
+/// When systemd socket-activates us, the socket belongs to it, so we leave it alone on exit. That
+/// way, systemd keeps listening and starts us again on the next connection
+struct SocketWrapper {
+    listener: UnixListener,
+    socket_activated: bool,
+}
+
 impl SocketWrapper {
     fn new() -> Result<Self, String> {
         let socket_addr = get_socket_path();
+        let (listener, socket_activated) = match Self::from_systemd()? {
+            Some(listener) => {
+                let path = listener.local_addr().ok();
+                let path = path.as_ref().and_then(|addr| addr.as_pathname());
+                if path != Some(socket_addr.as_path()) {
+                    warn!(
+                        "systemd passed us the socket at {path:?}, but swww clients connect to \
+                        {socket_addr:?}. Make sure the ListenStream of the socket unit matches it"
+                    );
+                }
+                (listener, true)
+            }
+            None => (Self::bind(&socket_addr)?, false),
+        };
+
+        debug!(
+            "Made socket in {:?} and initialized logger. Starting daemon...",
+            listener.local_addr().unwrap() //this should always work if the socket connected correctly
+        );
+
+        if let Err(e) = listener.set_nonblocking(true) {
+            if !socket_activated {
+                let _ = fs::remove_file(&socket_addr);
+            }
+            return Err(format!("failed to set socket to nonblocking mode: {e}"));
+        }
+
+        Ok(Self {
+            listener,
+            socket_activated,
+        })
+    }
+
+    /// The listening socket passed by systemd through `LISTEN_FDS`, if there is one
+    fn from_systemd() -> Result<Option<UnixListener>, String> {
+        let mut fds = match sd_notify::listen_fds() {
+            Ok(fds) => fds,
+            Err(e) => return Err(format!("failed to read the sockets passed by systemd: {e}")),
+        };
+        let fd = match fds.next() {
+            Some(fd) => fd,
+            None => return Ok(None),
+        };
+        if fds.next().is_some() {
+            warn!("systemd passed us more than one socket. Only the first one will be used");
+        }
+        match nix::sys::socket::getsockopt(
+            &unsafe { BorrowedFd::borrow_raw(fd) },
+            nix::sys::socket::sockopt::AcceptConn,
+        ) {
+            Ok(true) => (),
+            Ok(false) => return Err("the socket passed by systemd is not listening".to_string()),
+            Err(e) => return Err(format!("invalid socket passed by systemd: {e}")),
+        }
+        info!("Using the socket passed by systemd");
+        // SAFETY: systemd gives us ownership of the file descriptors it passes us, and
+        // `listen_fds` only returns them once
+        Ok(Some(unsafe { UnixListener::from_raw_fd(fd) }))
+    }
+
+    fn bind(socket_addr: &std::path::Path) -> Result<UnixListener, String> {
         let runtime_dir = match socket_addr.parent() {
             Some(path) => path,
             None => return Err("couldn't find a valid runtime directory".to_owned()),
@@ -198,27 +335,19 @@ impl SocketWrapper {
             }
         }
 
-        let listener = match UnixListener::bind(socket_addr.clone()) {
-            Ok(address) => address,
-            Err(e) => return Err(format!("couldn't bind socket: {e}")),
-        };
-
-        debug!(
-            "Made socket in {:?} and initialized logger. Starting daemon...",
-            listener.local_addr().unwrap() //this should always work if the socket connected correctly
-        );
-
-        if let Err(e) = listener.set_nonblocking(true) {
-            let _ = fs::remove_file(&socket_addr);
-            return Err(format!("failed to set socket to nonblocking mode: {e}"));
+        match UnixListener::bind(socket_addr) {
+            Ok(listener) => Ok(listener),
+            Err(e) => Err(format!("couldn't bind socket: {e}")),
         }
-
-        Ok(Self(listener))
     }
 }
 
 impl Drop for SocketWrapper {
     fn drop(&mut self) {
+        if self.socket_activated {
+            info!("Leaving the socket to systemd");
+            return;
+        }
         let socket_addr = get_socket_path();
         if let Err(e) = fs::remove_file(&socket_addr) {
             error!("Failed to remove socket at {socket_addr:?}: {e}");
@@ -227,6 +356,47 @@ impl Drop for SocketWrapper {
     }
 }
 
+/// How long we wait for `swww init` to restore the cache before we manage the outputs ourselves
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the client that started us may still be restoring the cache. Until it is done, we
+/// don't restore outputs nor apply the schedule by ourselves, so that we don't overwrite each
+/// other's images. Not every daemon is started by `swww init` (see socket activation, or
+/// `swww-daemon` run by hand), so we stop waiting after [`RESTORE_TIMEOUT`]
+struct Initialization {
+    /// When we stop waiting, or `None` if we already did
+    deadline: Option<Instant>,
+}
+
+impl Initialization {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.deadline = None;
+    }
+
+    fn is_done(&mut self) -> bool {
+        if self
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            info!("no client restored the cache, managing the outputs ourselves");
+            self.deadline = None;
+        }
+        self.deadline.is_none()
+    }
+
+    /// How long until we stop waiting, if we still are
+    fn timeout(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
 struct Daemon {
     // Wayland stuff
     layer_shell: LayerShell,
@@ -234,15 +404,29 @@ struct Daemon {
     registry_state: RegistryState,
     output_state: OutputState,
     shm: Shm,
+    /// Both are needed for fractional scaling, which we only do when the compositor supports it
+    fractional_scaling: Option<(WpViewporter, WpFractionalScaleManagerV1)>,
 
     // swww stuff
+    layer: Layer,
+    layer_namespace: String,
+    /// Whether systemd owns our socket, in which case it stays after we exit
+    socket_activated: bool,
     wallpapers: Vec<Arc<Wallpaper>>,
     animator: Animator,
-    initializing: bool,
+    playlists: Playlists,
+    scheduler: Scheduler,
+    initialization: Initialization,
+    max_message_size: usize,
 }
 
 impl Daemon {
-    fn new(globals: &GlobalList, qh: &QueueHandle<Self>) -> Self {
+    fn new(
+        globals: &GlobalList,
+        qh: &QueueHandle<Self>,
+        cli: cli::Cli,
+        socket_activated: bool,
+    ) -> Self {
         // The compositor (not to be confused with the server which is commonly called the compositor) allows
         // configuring surfaces to be presented.
         let compositor_state =
@@ -252,6 +436,16 @@ impl Daemon {
 
         let shm = Shm::bind(globals, qh).expect("wl_shm is not available");
 
+        let fractional_scaling = match (globals.bind(qh, 1..=1, ()), globals.bind(qh, 1..=1, ())) {
+            (Ok(viewporter), Ok(fractional_scale_manager)) => {
+                Some((viewporter, fractional_scale_manager))
+            }
+            _ => {
+                info!("fractional scaling is not available, using integer scales");
+                None
+            }
+        };
+
         Self {
             // Outputs may be hotplugged at runtime, therefore we need to setup a registry state to
             // listen for Outputs.
@@ -260,51 +454,60 @@ impl Daemon {
             compositor_state,
             shm,
             layer_shell,
+            fractional_scaling,
 
+            layer: cli.layer.into(),
+            layer_namespace: cli.layer_namespace,
+            socket_activated,
             wallpapers: Vec::new(),
             animator: Animator::new(),
-            initializing: true,
+            playlists: Playlists::default(),
+            scheduler: Scheduler::load(),
+            initialization: Initialization::new(RESTORE_TIMEOUT),
+            max_message_size: max_message_size(),
         }
     }
 
     fn recv_socket_msg(&mut self, stream: UnixStream) {
-        let bytes = match utils::ipc::read_socket(&stream) {
-            Ok(bytes) => bytes,
+        if let Err(e) = set_client_timeouts(&stream) {
+            warn!("refusing connection: failed to set its timeouts: {e}");
+            return;
+        }
+        match daemon_handshake(&stream) {
+            Ok(Some(_)) => (),
+            // the client was only checking whether we are running
+            Ok(None) => return,
             Err(e) => {
-                error!("FATAL: cannot read socket: {e}. Exiting...");
-                exit_daemon();
+                warn!("refusing connection: {e}");
+                return;
+            }
+        }
+        let valid = match read_request(&stream, self.max_message_size) {
+            Ok(valid) => valid,
+            Err(e) => {
+                reject_request(&stream, e);
                 return;
             }
         };
-        let request = Request::receive(&bytes);
+        let request = valid.get();
+        let subscribe = matches!(request, ArchivedRequest::Subscribe);
         let answer = match request {
             ArchivedRequest::Animation(animations) => {
                 let mut wallpapers = Vec::new();
-                for (_, names) in animations.iter() {
+                for (_, names) in animations.1.iter() {
                     wallpapers.push(self.find_wallpapers_by_names(names));
                 }
-                self.animator.animate(bytes, wallpapers)
+                self.animator.animate(valid, wallpapers)
             }
             ArchivedRequest::Clear(clear) => {
-                self.initializing = false;
+                self.initialization.finish();
                 let wallpapers = self.find_wallpapers_by_names(&clear.outputs);
-                let color = clear.color;
-                match std::thread::Builder::new()
-                    .stack_size(1 << 15)
-                    .name("clear".to_string())
-                    .spawn(move || {
-                        for wallpaper in &wallpapers {
-                            wallpaper.stop_animations();
-                        }
-                        for wallpaper in wallpapers {
-                            wallpaper.set_img_info(utils::ipc::BgImg::Color(color));
-                            wallpaper.clear(color);
-                            wallpaper.draw();
-                        }
-                        wake_poll();
-                    }) {
-                    Ok(_) => Answer::Ok,
-                    Err(e) => Answer::Err(format!("failed to spawn `clear` thread: {e}")),
+                for wallpaper in &wallpapers {
+                    self.playlists.stop(wallpaper.name());
+                }
+                match spawn_clear(wallpapers, clear.color) {
+                    Ok(()) => Answer::Ok,
+                    Err(e) => Answer::Err(e),
                 }
             }
             ArchivedRequest::Ping => Answer::Ping(
@@ -314,25 +517,171 @@ impl Daemon {
             ),
             ArchivedRequest::Kill => {
                 exit_daemon();
-                Answer::Ok
+                Answer::Killed {
+                    removes_socket: !self.socket_activated,
+                }
             }
             ArchivedRequest::Query => Answer::Info(self.wallpapers_info()),
+            ArchivedRequest::Subscribe => Answer::Ok,
+            ArchivedRequest::Pause(outputs) => {
+                for wallpaper in self.find_wallpapers_by_names(outputs) {
+                    wallpaper.pause();
+                }
+                Answer::Ok
+            }
+            ArchivedRequest::Resume(outputs) => {
+                for wallpaper in self.find_wallpapers_by_names(outputs) {
+                    wallpaper.resume();
+                }
+                Answer::Ok
+            }
+            ArchivedRequest::Step(step) => {
+                for wallpaper in self.find_wallpapers_by_names(&step.outputs) {
+                    wallpaper.step(step.frames);
+                }
+                Answer::Ok
+            }
+            ArchivedRequest::Visibility(visibility) => {
+                for wallpaper in self.find_wallpapers_by_names(&visibility.outputs) {
+                    wallpaper.set_hidden(!visibility.visible);
+                }
+                Answer::Ok
+            }
             ArchivedRequest::Img((_, imgs)) => {
-                self.initializing = false;
+                self.initialization.finish();
                 let mut used_wallpapers = Vec::new();
                 for img in imgs.iter() {
                     let mut wallpapers = self.find_wallpapers_by_names(&img.1);
                     for wallpaper in wallpapers.iter_mut() {
+                        self.playlists.stop(wallpaper.name());
                         wallpaper.stop_animations();
                     }
                     used_wallpapers.push(wallpapers);
                 }
-                self.animator.transition(bytes, used_wallpapers);
+                self.animator.transition(valid, used_wallpapers);
+                Answer::Ok
+            }
+            ArchivedRequest::Playlist(playlist) => {
+                self.initialization.finish();
+                let wallpapers = self.find_wallpapers_by_names(&playlist.outputs);
+                let playlist: ipc::Playlist = playlist.deserialize(&mut rkyv::Infallible).unwrap();
+                self.start_playlist(playlist, wallpapers)
+            }
+            ArchivedRequest::Next(outputs) => self.switch_playlists(outputs, 1),
+            ArchivedRequest::Prev(outputs) => self.switch_playlists(outputs, -1),
+            ArchivedRequest::ReloadSchedule => match self.scheduler.reload() {
+                Ok(()) => Answer::Ok,
+                Err(e) => Answer::Err(e),
+            },
+            ArchivedRequest::Restored => {
+                self.initialization.finish();
                 Answer::Ok
             }
         };
         if let Err(e) = answer.send(&stream) {
             error!("error sending answer to client: {e}");
+        } else if subscribe {
+            events::subscribe(stream);
+        }
+    }
+
+    fn start_playlist(
+        &mut self,
+        playlist: ipc::Playlist,
+        wallpapers: Vec<Arc<Wallpaper>>,
+    ) -> Answer {
+        if playlist.paths.is_empty() {
+            return Answer::Err("the playlist is empty".to_string());
+        }
+        if playlist.interval.is_zero() {
+            return Answer::Err("the playlist interval must be positive".to_string());
+        }
+        let position = playlist.position as usize % playlist.paths.len();
+        let img = CachedImg {
+            path: playlist.paths[position].clone(),
+            resize: playlist.resize,
+            filter: playlist.filter,
+            fill_color: playlist.fill_color,
+            transition: Some(playlist.transition),
+            playlist: Some(CachedPlaylist {
+                paths: playlist.paths.into_vec(),
+                position,
+                interval: playlist.interval,
+            }),
+            ..CachedImg::new(String::new())
+        };
+        for wallpaper in wallpapers {
+            let output = wallpaper.name().to_string();
+            self.playlists.start(&output, img.clone());
+            if playlist.immediate {
+                let img = CachedImg {
+                    transition: None,
+                    ..img.clone()
+                };
+                self.animator.display(wallpaper, img);
+            } else {
+                self.animator.display(wallpaper, img.clone());
+            }
+            if let Err(e) = cache::store(&output, &img) {
+                error!("failed to store playlist of output {output}: {e}");
+            }
+        }
+        Answer::Ok
+    }
+
+    /// Moves the playlists in the outputs in `names` (or all of them, if empty) by `offset` images
+    fn switch_playlists(&mut self, names: &ArchivedBox<[ArchivedString]>, offset: isize) -> Answer {
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        let outputs: Vec<String> = self.playlists.outputs(&names).collect();
+        if outputs.is_empty() {
+            return Answer::Err(
+                "there are no playlists running in the requested outputs".to_string(),
+            );
+        }
+        for output in outputs {
+            if let Some(img) = self.playlists.advance(&output, offset) {
+                self.show_cached_img(&output, img);
+            }
+        }
+        Answer::Ok
+    }
+
+    /// Switches the images of the playlists whose time has come
+    fn advance_playlists(&mut self) {
+        for (output, img) in self.playlists.advance_due() {
+            self.show_cached_img(&output, img);
+        }
+    }
+
+    /// Switches the images of the outputs whose scheduled image changed. We wait until the cache
+    /// was restored, so that the client doesn't overwrite the scheduled images with the cached ones
+    fn apply_schedule(&mut self) {
+        if !self.initialization.is_done() {
+            return;
+        }
+        let outputs = self
+            .wallpapers
+            .iter()
+            .filter(|w| w.configured.load(Ordering::Acquire))
+            .map(|w| w.name());
+        for (output, img) in self.scheduler.due(outputs) {
+            self.playlists.stop(&output);
+            self.show_cached_img(&output, img);
+        }
+    }
+
+    fn show_cached_img(&mut self, output: &str, img: CachedImg) {
+        // this also saves the playlist's position, in case we restart
+        if let Err(e) = cache::store(output, &img) {
+            error!("failed to store cache of output {output}: {e}");
+        }
+        // unconfigured outputs will restore the image from the cache once they are configured
+        if let Some(wallpaper) = self
+            .wallpapers
+            .iter()
+            .find(|w| w.name() == output && w.configured.load(Ordering::Acquire))
+        {
+            self.animator.display(Arc::clone(wallpaper), img);
         }
     }
 
@@ -343,13 +692,17 @@ impl Daemon {
                 if let Some(info) = self.output_state.info(&output) {
                     if let Some(wallpaper) = self.wallpapers.iter().find(|w| w.has_id(info.id)) {
                         return Some(BgInfo {
-                            name: info.name.unwrap_or("?".to_string()),
-                            dim: info
-                                .logical_size
+                            name: info.name.clone().unwrap_or("?".to_string()),
+                            dim: wallpaper::logical_size(&info)
                                 .map(|(width, height)| (width as u32, height as u32))
                                 .unwrap_or((0, 0)),
+                            position: info.logical_position.unwrap_or(info.location),
                             scale_factor: info.scale_factor,
+                            fractional_scale: wallpaper.get_fractional_scale(),
+                            transform: wallpaper.get_transform(),
                             img: wallpaper.get_img_info(),
+                            animating: wallpaper.is_animation_running(),
+                            transitioning: wallpaper.is_transition_running(),
                         });
                     }
                 }
@@ -382,6 +735,30 @@ impl Daemon {
     }
 }
 
+/// How long a client may keep us waiting in a single read or write. We talk to clients on the
+/// main thread, so one that stalls would otherwise freeze the whole daemon
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn set_client_timeouts(stream: &UnixStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))
+}
+
+/// Reads a request from the socket, making sure it is a valid archived `Request` before anyone
+/// gets to look at it
+fn read_request(stream: &UnixStream, max_len: usize) -> Result<ValidRequest, MessageError> {
+    ValidRequest::new(read_message(stream, max_len)?)
+}
+
+/// Tells the client its request could not be processed. We do not exit, since a bad client
+/// shouldn't be able to take the daemon down with it
+fn reject_request(stream: &UnixStream, error: MessageError) {
+    warn!("rejecting malformed request: {error}");
+    if let Err(e) = Answer::Malformed(error).send(stream) {
+        error!("error sending answer to client: {e}");
+    }
+}
+
 impl CompositorHandler for Daemon {
     fn scale_factor_changed(
         &mut self,
@@ -420,7 +797,9 @@ impl CompositorHandler for Daemon {
         _surface: &wl_surface::WlSurface,
         _new_transform: wl_output::Transform,
     ) {
-        // do not do anything for now
+        // this is only the buffer transform the compositor would prefer. We keep drawing upright,
+        // since our size is already in logical coordinates, so the compositor takes care of the
+        // rotation. The transform we report is the output's, which `update_output` keeps track of
     }
 }
 
@@ -447,35 +826,37 @@ impl OutputHandler for Daemon {
             let layer_surface = self.layer_shell.create_layer_surface(
                 qh,
                 surface,
-                Layer::Background,
-                Some("swww"),
+                self.layer,
+                Some(self.layer_namespace.clone()),
                 Some(&output),
             );
 
-            if !self.initializing {
-                if let Some(name) = &output_info.name {
-                    let name = name.to_owned();
-                    if let Err(e) = std::thread::Builder::new()
-                        .name("cache loader".to_string())
-                        .stack_size(1 << 14)
-                        .spawn(move || {
-                            // Wait for a bit for the output to be properly configured and stuff
-                            // this is obviously not ideal, but it solves the vast majority of problems
-                            std::thread::sleep(std::time::Duration::from_millis(100));
-                            if let Err(e) = utils::cache::load(&name) {
-                                warn!("failed to load cache: {e}");
-                            }
-                        })
-                    {
-                        warn!("failed to spawn `cache loader` thread: {e}");
-                    }
-                }
-            }
-
+            events::emit(Event::OutputAdded {
+                output: output_info.name.clone().unwrap_or("?".to_string()),
+                dim: wallpaper::logical_size(&output_info)
+                    .map(|(width, height)| (width as u32, height as u32))
+                    .unwrap_or((0, 0)),
+                scale_factor: output_info.scale_factor,
+            });
             debug!("New output: {output_info:?}");
+            let viewport =
+                self.fractional_scaling
+                    .as_ref()
+                    .map(|(viewporter, fractional_scale_manager)| {
+                        let surface = layer_surface.wl_surface();
+                        Viewport {
+                            viewport: viewporter.get_viewport(surface, qh, ()),
+                            fractional_scale: fractional_scale_manager.get_fractional_scale(
+                                surface,
+                                qh,
+                                surface.clone(),
+                            ),
+                        }
+                    });
             self.wallpapers.push(Arc::new(Wallpaper::new(
                 output_info,
                 layer_surface,
+                viewport,
                 &self.shm,
                 qh,
             )));
@@ -490,24 +871,28 @@ impl OutputHandler for Daemon {
         output: wl_output::WlOutput,
     ) {
         if let Some(output_info) = self.output_state.info(&output) {
-            if let Some(output_size) = output_info.logical_size {
-                if output_size.0 == 0 || output_size.1 == 0 {
+            if let Some(wallpaper) = self.wallpapers.iter().find(|w| w.has_id(output_info.id)) {
+                wallpaper.set_transform(output_info.transform);
+            }
+            let output_size = match wallpaper::logical_size(&output_info) {
+                Some(output_size) => output_size,
+                None => {
                     error!(
                         "output dimensions cannot be '0'. Received: {:#?}",
-                        output_size
+                        output_info.logical_size
                     );
                     return;
                 }
-                for wallpaper in self.wallpapers.iter_mut() {
-                    if wallpaper.has_id(output_info.id) {
-                        let (width, height) = (
-                            Some(NonZeroI32::new(output_size.0).unwrap()),
-                            Some(NonZeroI32::new(output_size.1).unwrap()),
-                        );
-                        let scale_factor = Some(NonZeroI32::new(output_info.scale_factor).unwrap());
-                        wallpaper.resize(width, height, scale_factor);
-                        return;
-                    }
+            };
+            for wallpaper in self.wallpapers.iter_mut() {
+                if wallpaper.has_id(output_info.id) {
+                    let (width, height) = (
+                        Some(NonZeroI32::new(output_size.0).unwrap()),
+                        Some(NonZeroI32::new(output_size.1).unwrap()),
+                    );
+                    let scale_factor = Some(NonZeroI32::new(output_info.scale_factor).unwrap());
+                    wallpaper.resize(width, height, scale_factor);
+                    return;
                 }
             }
         }
@@ -521,6 +906,9 @@ impl OutputHandler for Daemon {
     ) {
         if let Some(output_info) = self.output_state.info(&output) {
             self.wallpapers.retain(|w| !w.has_id(output_info.id));
+            events::emit(Event::OutputRemoved {
+                output: output_info.name.clone().unwrap_or("?".to_string()),
+            });
             debug!("Destroyed output: {output_info:?}");
         }
     }
@@ -550,8 +938,34 @@ impl LayerShellHandler for Daemon {
         // configured
         for w in &mut self.wallpapers {
             if w.has_surface(layer.wl_surface()) {
-                w.configured
-                    .store(true, std::sync::atomic::Ordering::Release);
+                let was_configured = w.configured.swap(true, std::sync::atomic::Ordering::AcqRel);
+                // outputs connected after initialization get their last image back. We only do
+                // this now because we can't draw to the surface before its first configure
+                if !was_configured && self.initialization.is_done() {
+                    match w.restore() {
+                        Restore::Cache => {
+                            // resume the output's playlist, unless it is still running from
+                            // before the output was disconnected
+                            let output = w.name();
+                            if !self.playlists.contains(output) {
+                                match cache::get_previous_image(output) {
+                                    Ok(Some(cached)) => self.playlists.start(output, cached),
+                                    Ok(None) => (),
+                                    Err(e) => {
+                                        warn!("failed to load cache for output {output}: {e}")
+                                    }
+                                }
+                            }
+                            self.animator.restore(Arc::clone(w));
+                            self.scheduler.check_soon();
+                        }
+                        Restore::Color(color) => {
+                            if let Err(e) = spawn_clear(vec![Arc::clone(w)], color) {
+                                error!("{e}");
+                            }
+                        }
+                    }
+                }
                 break;
             }
         }
@@ -576,6 +990,61 @@ impl Dispatch<WlBuffer, Arc<AtomicBool>> for Daemon {
     }
 }
 
+impl Dispatch<WpFractionalScaleV1, wl_surface::WlSurface> for Daemon {
+    fn event(
+        state: &mut Self,
+        _proxy: &WpFractionalScaleV1,
+        event: wp_fractional_scale_v1::Event,
+        surface: &wl_surface::WlSurface,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wp_fractional_scale_v1::Event::PreferredScale { scale } = event {
+            if let Some(wallpaper) = state.wallpapers.iter().find(|w| w.has_surface(surface)) {
+                debug!("output {} - preferred scale: {scale}/120", wallpaper.name());
+                wallpaper.set_fractional_scale(scale);
+            }
+        }
+    }
+}
+
+// these have no events
+impl Dispatch<WpViewporter, ()> for Daemon {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewporter,
+        _event: <WpViewporter as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpViewport, ()> for Daemon {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpViewport,
+        _event: <WpViewport as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WpFractionalScaleManagerV1, ()> for Daemon {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WpFractionalScaleManagerV1,
+        _event: <WpFractionalScaleManagerV1 as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+    }
+}
+
 delegate_compositor!(Daemon);
 delegate_output!(Daemon);
 delegate_shm!(Daemon);
@@ -591,17 +1060,129 @@ impl ProvidesRegistryState for Daemon {
     registry_handlers![OutputState];
 }
 
-fn make_logger() {
-    let config = simplelog::ConfigBuilder::new()
-        .set_thread_level(LevelFilter::Error) // let me see where the processing is happening
-        .set_thread_mode(ThreadLogMode::Both)
-        .build();
-
-    TermLogger::init(
-        LevelFilter::Debug,
-        config,
-        TerminalMode::Stderr,
-        ColorChoice::AlwaysAnsi,
-    )
-    .expect("Failed to initialize logger. Cancelling...");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::random;
+    use std::io::Write;
+    use utils::ipc::Request;
+
+    const MAX_LEN: usize = 1 << 16;
+
+    /// Writes `bytes` to one end of a socket pair, and tries to read a request from the other
+    fn recv(bytes: &[u8]) -> Result<ValidRequest, MessageError> {
+        let (mut client, daemon) = UnixStream::pair().unwrap();
+        client.write_all(bytes).unwrap();
+        drop(client);
+        read_request(&daemon, MAX_LEN)
+    }
+
+    fn with_len(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = payload.len().to_ne_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn stops_waiting_for_the_cache_to_be_restored() {
+        let mut initialization = Initialization::new(Duration::from_secs(60));
+        assert!(!initialization.is_done());
+        assert!(initialization.timeout().unwrap() > Duration::from_secs(50));
+        initialization.finish();
+        assert!(initialization.is_done());
+        assert_eq!(initialization.timeout(), None);
+
+        // no client ever tells us it restored the cache
+        let mut initialization = Initialization::new(Duration::ZERO);
+        assert!(initialization.is_done());
+    }
+
+    #[test]
+    fn applies_a_schedule_without_any_request() {
+        // a daemon that nobody sent an image to: all it has is its schedule
+        let mut initialization = Initialization::new(Duration::ZERO);
+        let schedule = "00:00-24:00 /night.png".parse().unwrap();
+        let mut scheduler = Scheduler::new(Some(schedule));
+        assert!(initialization.is_done());
+        let due = scheduler.due(["swww-test-output"].into_iter());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "swww-test-output");
+        assert_eq!(due[0].1.path, "/night.png");
+    }
+
+    #[test]
+    fn accepts_valid_request() {
+        let request = rkyv::to_bytes::<_, 1024>(&Request::Ping).unwrap();
+        let valid = recv(&with_len(&request)).unwrap();
+        assert!(matches!(valid.get(), ArchivedRequest::Ping));
+    }
+
+    #[test]
+    fn rejects_oversized_length_prefix() {
+        let mut bytes = usize::MAX.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 32]);
+        assert!(matches!(recv(&bytes), Err(MessageError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn gives_up_on_silent_clients() {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        set_client_timeouts(&daemon).unwrap();
+        let start = Instant::now();
+        assert!(daemon_handshake(&daemon).is_err());
+        assert!(start.elapsed() < CLIENT_TIMEOUT * 2);
+
+        // a client that stops partway through its handshake
+        (&client).write_all(&[0; 3]).unwrap();
+        assert!(daemon_handshake(&daemon).is_err());
+        drop(client);
+    }
+
+    #[test]
+    fn rejects_truncated_request() {
+        let request = rkyv::to_bytes::<_, 1024>(&Request::Clear(utils::ipc::Clear {
+            color: [1, 2, 3],
+            outputs: vec!["HDMI-A-1".to_string()].into_boxed_slice(),
+        }))
+        .unwrap();
+        let bytes = with_len(&request);
+        for i in 0..bytes.len() {
+            assert!(recv(&bytes[..i]).is_err(), "accepted {i} truncated bytes");
+        }
+    }
+
+    #[test]
+    fn survives_random_bytes() {
+        for _ in 0..1000 {
+            let len = random::<usize>() % 512;
+            let payload: Vec<u8> = (0..len).map(|_| random()).collect();
+            // we only care that this doesn't crash
+            let _ = recv(&with_len(&payload));
+            let _ = recv(&payload);
+        }
+    }
+
+    #[test]
+    fn survives_corrupted_requests() {
+        let request = rkyv::to_bytes::<_, 1024>(&Request::Clear(utils::ipc::Clear {
+            color: [1, 2, 3],
+            outputs: vec!["HDMI-A-1".to_string(), "DP-1".to_string()].into_boxed_slice(),
+        }))
+        .unwrap();
+        for _ in 0..1000 {
+            let mut payload = request.to_vec();
+            for _ in 0..1 + random::<usize>() % 4 {
+                let i = random::<usize>() % payload.len();
+                payload[i] = random();
+            }
+            if let Ok(valid) = recv(&with_len(&payload)) {
+                // if it validated, it must be safe to walk the whole request
+                if let ArchivedRequest::Clear(clear) = valid.get() {
+                    for output in clear.outputs.iter() {
+                        let _ = output.as_str().len();
+                    }
+                }
+            }
+        }
+    }
 }
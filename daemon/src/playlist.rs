@@ -0,0 +1,140 @@
+//! Slideshows the daemon runs by itself, switching the image of an output every so often
+//!
+//! Their state lives in the cache entry of each output (see [`CachedImg::playlist`]), which we
+//! rewrite on every switch, so that they resume where they left off when the daemon restarts
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use utils::cache::CachedImg;
+
+struct Playlist {
+    /// The current image, with the playlist in it
+    img: CachedImg,
+    next_switch: Instant,
+}
+
+/// The playlists of every output, by output name
+#[derive(Default)]
+pub(super) struct Playlists(HashMap<String, Playlist>);
+
+impl Playlists {
+    /// Starts running `img`'s playlist on `output`, replacing the previous one
+    pub(super) fn start(&mut self, output: &str, img: CachedImg) {
+        let interval = match &img.playlist {
+            Some(playlist) => playlist.interval,
+            None => return,
+        };
+        let next_switch = Instant::now() + interval;
+        self.0
+            .insert(output.to_string(), Playlist { img, next_switch });
+    }
+
+    pub(super) fn stop(&mut self, output: &str) {
+        self.0.remove(output);
+    }
+
+    pub(super) fn contains(&self, output: &str) -> bool {
+        self.0.contains_key(output)
+    }
+
+    /// Moves `output`'s playlist `offset` images forward (or backwards, if negative), returning
+    /// its new image. This restarts the countdown to the next switch
+    pub(super) fn advance(&mut self, output: &str, offset: isize) -> Option<CachedImg> {
+        let playlist = self.0.get_mut(output)?;
+        let state = playlist.img.playlist.as_mut()?;
+        let len = state.paths.len() as isize;
+        state.position = (state.position as isize + offset).rem_euclid(len) as usize;
+        playlist.img.path = state.paths[state.position].clone();
+        playlist.next_switch = Instant::now() + state.interval;
+        Some(playlist.img.clone())
+    }
+
+    /// Advances every playlist whose time has come, returning their outputs and new images
+    pub(super) fn advance_due(&mut self) -> Vec<(String, CachedImg)> {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(_, playlist)| playlist.next_switch <= now)
+            .map(|(output, _)| output.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|output| {
+                let img = self.advance(&output, 1)?;
+                Some((output, img))
+            })
+            .collect()
+    }
+
+    /// How long until the next playlist switches images, if there are any
+    pub(super) fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.0
+            .values()
+            .map(|playlist| playlist.next_switch.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Outputs with a playlist among `names`, or all of them if it is empty
+    pub(super) fn outputs<'a>(&'a self, names: &'a [&str]) -> impl Iterator<Item = String> + 'a {
+        self.0
+            .keys()
+            .filter(|output| names.is_empty() || names.contains(&output.as_str()))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::cache::CachedPlaylist;
+
+    fn playlist(interval: Duration) -> CachedImg {
+        let paths = vec![
+            "/a.png".to_string(),
+            "/b.png".to_string(),
+            "/c.png".to_string(),
+        ];
+        CachedImg {
+            playlist: Some(CachedPlaylist {
+                paths: paths.clone(),
+                position: 0,
+                interval,
+            }),
+            ..CachedImg::new(paths[0].clone())
+        }
+    }
+
+    #[test]
+    fn advancing_wraps_around() {
+        let mut playlists = Playlists::default();
+        playlists.start("DP-1", playlist(Duration::from_secs(60)));
+        assert_eq!(playlists.advance("DP-1", 1).unwrap().path, "/b.png");
+        assert_eq!(playlists.advance("DP-1", 2).unwrap().path, "/a.png");
+        let img = playlists.advance("DP-1", -1).unwrap();
+        assert_eq!(img.path, "/c.png");
+        assert_eq!(img.playlist.unwrap().position, 2);
+        assert!(playlists.advance("HDMI-A-1", 1).is_none());
+    }
+
+    #[test]
+    fn only_due_playlists_advance() {
+        let mut playlists = Playlists::default();
+        playlists.start("DP-1", playlist(Duration::ZERO));
+        playlists.start("DP-2", playlist(Duration::from_secs(60)));
+        assert_eq!(playlists.timeout(), Some(Duration::ZERO));
+
+        let due = playlists.advance_due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "DP-1");
+        assert_eq!(due[0].1.path, "/b.png");
+
+        playlists.stop("DP-1");
+        assert!(playlists.timeout().unwrap() > Duration::from_secs(59));
+        assert_eq!(playlists.outputs(&[]).collect::<Vec<_>>(), ["DP-2"]);
+        assert!(playlists.outputs(&["DP-1"]).next().is_none());
+    }
+}
@@ -0,0 +1,46 @@
+//! `swww-daemon --preview`: renders a transition without a compositor, so that `swww preview` can
+//! encode it to a file.
+//!
+//! We read an archived [`Preview`] from stdin, and write every frame to stdout, in the same BGRx
+//! format we draw in
+use std::io::{self, Read, Write};
+
+use rkyv::AlignedVec;
+use utils::ipc::Preview;
+
+use crate::animations;
+
+pub(super) fn run() -> Result<(), String> {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("failed to read the preview: {e}"))?;
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(&bytes);
+    drop(bytes);
+    let preview = Preview::receive(&aligned)?;
+
+    let (width, height) = (preview.dimensions.0, preview.dimensions.1);
+    let len = width as usize * height as usize * 3;
+    if width == 0 || height == 0 || preview.from.len() != len || preview.to.len() != len {
+        return Err(format!(
+            "the preview images do not match its dimensions of {width}x{height}"
+        ));
+    }
+
+    let frames = animations::preview(
+        &preview.transition,
+        (width, height),
+        &preview.from,
+        &preview.to,
+    );
+    let mut stdout = io::stdout().lock();
+    for frame in frames {
+        stdout
+            .write_all(&frame)
+            .map_err(|e| format!("failed to write a frame: {e}"))?;
+    }
+    stdout
+        .flush()
+        .map_err(|e| format!("failed to write a frame: {e}"))
+}
@@ -0,0 +1,218 @@
+//! Where a [`Wallpaper`](crate::wallpaper::Wallpaper) puts the frames it draws.
+//!
+//! Normally that is a layer-shell surface, but the wallpaper logic (transitions, animations,
+//! clears) doesn't care, so we can also draw to memory, without a compositor, for tests and for
+//! `swww preview`
+use std::num::NonZeroI32;
+
+use smithay_client_toolkit::{
+    reexports::protocols::wp::{
+        fractional_scale::v1::client::wp_fractional_scale_v1::WpFractionalScaleV1,
+        viewporter::client::wp_viewport::WpViewport,
+    },
+    shell::{
+        wlr_layer::{Anchor, KeyboardInteractivity, LayerSurface},
+        WaylandSurface,
+    },
+    shm::Shm,
+};
+use wayland_client::{protocol::wl_surface::WlSurface, QueueHandle};
+
+use crate::{bump_pool::BumpPool, Daemon};
+
+pub(super) trait RenderTarget: Send + Sync {
+    /// The buffer to draw the next frame in, with 4 bytes per pixel (BGRx). It always starts with
+    /// the contents of the previous frame
+    fn drawable(&mut self) -> &mut [u8];
+
+    /// Shows the last frame we drew, which is `buffer_size` pixels large.
+    ///
+    /// Returns whether a frame callback will tell us when to draw the next frame. If not, we can
+    /// draw it right away
+    fn present(&mut self, buffer_size: (i32, i32)) -> bool;
+
+    /// Tells the target the integer scale of its output. Targets that scale their buffers some
+    /// other way can ignore it
+    fn set_scale_factor(&mut self, scale_factor: NonZeroI32);
+
+    /// Called when the logical size or the scale of the wallpaper changed. The old contents are
+    /// lost
+    fn resize(&mut self, size: (NonZeroI32, NonZeroI32), buffer_size: (i32, i32));
+
+    /// The surface we present to, if any. The wallpaper keeps a copy, so that it can tell which
+    /// surface Wayland events are about without locking the target
+    fn surface(&self) -> Option<WlSurface>;
+}
+
+/// Lets us draw at fractional scales, when the compositor supports it
+pub(super) struct Viewport {
+    pub(super) viewport: WpViewport,
+    pub(super) fractional_scale: WpFractionalScaleV1,
+}
+
+/// Draws to a layer-shell surface, through the buffers of a [`BumpPool`]
+pub(super) struct WaylandTarget {
+    layer_surface: LayerSurface,
+    viewport: Option<Viewport>,
+    pool: BumpPool,
+    qh: QueueHandle<Daemon>,
+}
+
+impl WaylandTarget {
+    pub(super) fn new(
+        layer_surface: LayerSurface,
+        viewport: Option<Viewport>,
+        size: (NonZeroI32, NonZeroI32),
+        scale_factor: NonZeroI32,
+        shm: &Shm,
+        qh: &QueueHandle<Daemon>,
+    ) -> Self {
+        let (width, height) = size;
+        // Configure the layer surface
+        layer_surface.set_anchor(Anchor::all());
+        layer_surface.set_exclusive_zone(-1);
+        layer_surface.set_margin(0, 0, 0, 0);
+        layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
+        layer_surface.set_size(width.get() as u32, height.get() as u32);
+        match &viewport {
+            // the buffer scale must stay 1 when we scale through the viewport
+            Some(viewport) => viewport.viewport.set_destination(width.get(), height.get()),
+            None => layer_surface
+                .set_buffer_scale(scale_factor.get() as u32)
+                .unwrap(),
+        }
+        // commit so that the compositor send the initial configuration
+        layer_surface.commit();
+        layer_surface
+            .wl_surface()
+            .frame(qh, layer_surface.wl_surface().clone());
+
+        // we only learn the fractional scale after the first configure
+        let w = width.get() * scale_factor.get();
+        let h = height.get() * scale_factor.get();
+        let pool = BumpPool::new(w, h, shm, qh);
+
+        Self {
+            layer_surface,
+            viewport,
+            pool,
+            qh: qh.clone(),
+        }
+    }
+}
+
+impl RenderTarget for WaylandTarget {
+    fn drawable(&mut self) -> &mut [u8] {
+        self.pool.get_drawable(&self.qh)
+    }
+
+    fn present(&mut self, buffer_size: (i32, i32)) -> bool {
+        let surface = self.layer_surface.wl_surface();
+        if let Some(buf) = self.pool.get_commitable_buffer() {
+            surface.attach(Some(buf), 0, 0);
+            surface.damage_buffer(0, 0, buffer_size.0, buffer_size.1);
+        }
+        // if there is no buffer, we still commit and send another frame request, since we
+        // consumed the previous one
+        surface.commit();
+        surface.frame(&self.qh, surface.clone());
+        true
+    }
+
+    fn set_scale_factor(&mut self, scale_factor: NonZeroI32) {
+        if self.viewport.is_none() {
+            self.layer_surface
+                .set_buffer_scale(scale_factor.get() as u32)
+                .unwrap();
+        }
+    }
+
+    fn resize(&mut self, size: (NonZeroI32, NonZeroI32), buffer_size: (i32, i32)) {
+        let (width, height) = size;
+        self.pool.resize(buffer_size.0, buffer_size.1, &self.qh);
+        self.layer_surface
+            .set_size(width.get() as u32, height.get() as u32);
+        if let Some(viewport) = &self.viewport {
+            viewport.viewport.set_destination(width.get(), height.get());
+        }
+        self.layer_surface.commit();
+        self.layer_surface
+            .wl_surface()
+            .frame(&self.qh, self.layer_surface.wl_surface().clone());
+    }
+
+    fn surface(&self) -> Option<WlSurface> {
+        Some(self.layer_surface.wl_surface().clone())
+    }
+}
+
+impl Drop for WaylandTarget {
+    fn drop(&mut self) {
+        if let Some(viewport) = &self.viewport {
+            viewport.fractional_scale.destroy();
+            viewport.viewport.destroy();
+        }
+    }
+}
+
+/// Every frame a [`Headless`] target presented, shared with whoever created it
+#[derive(Clone, Default)]
+pub(super) struct Frames(std::sync::Arc<std::sync::Mutex<Vec<Box<[u8]>>>>);
+
+impl Frames {
+    /// Removes and returns all the frames presented so far, oldest first
+    pub(super) fn take(&self) -> Vec<Box<[u8]>> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Draws to memory, recording every frame it presents. Since there is no compositor to wait for,
+/// frames can be drawn as fast as the wallpaper wants
+pub(super) struct Headless {
+    canvas: Box<[u8]>,
+    /// Whether we drew anything since the last resize. Like a surface without a buffer, we have
+    /// nothing to show until then
+    drawn: bool,
+    frames: Frames,
+}
+
+impl Headless {
+    pub(super) fn new(buffer_size: (i32, i32)) -> (Self, Frames) {
+        let frames = Frames::default();
+        let headless = Self {
+            canvas: canvas(buffer_size),
+            drawn: false,
+            frames: frames.clone(),
+        };
+        (headless, frames)
+    }
+}
+
+fn canvas(buffer_size: (i32, i32)) -> Box<[u8]> {
+    vec![0; buffer_size.0 as usize * buffer_size.1 as usize * 4].into_boxed_slice()
+}
+
+impl RenderTarget for Headless {
+    fn drawable(&mut self) -> &mut [u8] {
+        self.drawn = true;
+        &mut self.canvas
+    }
+
+    fn present(&mut self, _buffer_size: (i32, i32)) -> bool {
+        if self.drawn {
+            self.frames.0.lock().unwrap().push(self.canvas.clone());
+        }
+        false
+    }
+
+    fn set_scale_factor(&mut self, _scale_factor: NonZeroI32) {}
+
+    fn resize(&mut self, _size: (NonZeroI32, NonZeroI32), buffer_size: (i32, i32)) {
+        self.canvas = canvas(buffer_size);
+        self.drawn = false;
+    }
+
+    fn surface(&self) -> Option<WlSurface> {
+        None
+    }
+}
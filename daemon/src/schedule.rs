@@ -0,0 +1,117 @@
+//! Switches the images of the outputs according to the schedule file (see
+//! [`utils::schedule`])
+//!
+//! We only switch an output when its scheduled image changes, so images set by hand stay until
+//! the next boundary of the schedule
+
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use utils::{
+    cache::{self, CachedImg},
+    schedule::{LocalTime, Schedule},
+};
+
+pub(super) struct Scheduler {
+    schedule: Option<Schedule>,
+    /// The last image the schedule asked for, by output name
+    applied: HashMap<String, String>,
+    next_check: Instant,
+}
+
+impl Scheduler {
+    /// Reads the schedule, logging any errors, since the daemon works fine without one
+    pub(super) fn load() -> Self {
+        let mut scheduler = Self::new(None);
+        if let Err(e) = scheduler.reload() {
+            warn!("{e}");
+        }
+        scheduler
+    }
+
+    pub(super) fn new(schedule: Option<Schedule>) -> Self {
+        Self {
+            schedule,
+            applied: HashMap::new(),
+            next_check: Instant::now(),
+        }
+    }
+
+    /// Reads the schedule again. Not having one is fine, and disables scheduling
+    pub(super) fn reload(&mut self) -> Result<(), String> {
+        let path = Schedule::default_path()?;
+        if !path.exists() {
+            info!("no schedule found at {path:?}");
+            self.schedule = None;
+            return Ok(());
+        }
+        let schedule = Schedule::load(&path)?;
+        info!(
+            "loaded schedule {path:?} with {} entries",
+            schedule.entries.len()
+        );
+        self.schedule = Some(schedule);
+        self.check_soon();
+        Ok(())
+    }
+
+    /// Makes the next call to [`Self::due`] check the schedule, for example because an output
+    /// just became available
+    pub(super) fn check_soon(&mut self) {
+        self.next_check = Instant::now();
+    }
+
+    /// How long until we should check the schedule again, if there is one
+    pub(super) fn timeout(&self) -> Option<Duration> {
+        self.schedule.as_ref()?;
+        Some(self.next_check.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the outputs among `outputs` whose scheduled image changed, with their new images
+    pub(super) fn due<'a>(
+        &mut self,
+        outputs: impl Iterator<Item = &'a str>,
+    ) -> Vec<(String, CachedImg)> {
+        let schedule = match &self.schedule {
+            Some(schedule) if self.next_check <= Instant::now() => schedule,
+            _ => return Vec::new(),
+        };
+        let now = match LocalTime::now() {
+            Ok(now) => now,
+            Err(e) => {
+                warn!("{e}");
+                self.next_check = Instant::now() + Duration::from_secs(60);
+                return Vec::new();
+            }
+        };
+        // schedules have a resolution of one minute, so we check at the start of every minute
+        self.next_check = Instant::now() + Duration::from_secs(60 - now.second as u64);
+
+        let mut due = Vec::new();
+        for output in outputs {
+            let path = match schedule.image_for(output, &now) {
+                Some(path) => path,
+                None => continue,
+            };
+            // don't switch to the image the output is already displaying from before we started
+            let applied = self.applied.entry(output.to_string()).or_insert_with(|| {
+                match cache::get_previous_image(output) {
+                    Ok(Some(cached)) => cached.path,
+                    _ => String::new(),
+                }
+            });
+            if applied != path {
+                *applied = path.to_string();
+                let img = CachedImg {
+                    path: path.to_string(),
+                    ..schedule.options.clone()
+                };
+                due.push((output.to_string(), img));
+            }
+        }
+        due
+    }
+}
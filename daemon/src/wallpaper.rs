@@ -1,30 +1,56 @@
-use utils::ipc::BgImg;
+use utils::ipc::{BgImg, Event, Transform};
 
 use std::{
-    num::NonZeroI32,
+    num::{NonZeroI32, NonZeroU32},
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Condvar, Mutex, RwLock,
     },
+    time::Duration,
 };
 
-use smithay_client_toolkit::{
-    output::OutputInfo,
-    shell::{
-        wlr_layer::{Anchor, KeyboardInteractivity, LayerSurface},
-        WaylandSurface,
-    },
-    shm::Shm,
-};
+use smithay_client_toolkit::{output::OutputInfo, shell::wlr_layer::LayerSurface, shm::Shm};
 
-use wayland_client::{protocol::wl_surface::WlSurface, QueueHandle};
+use wayland_client::{
+    protocol::{wl_output, wl_surface::WlSurface},
+    QueueHandle,
+};
 
-use crate::{bump_pool::BumpPool, Daemon};
+use crate::{
+    render_target::{RenderTarget, Viewport, WaylandTarget},
+    Daemon,
+};
 
 #[derive(Debug)]
 struct AnimationState {
     id: AtomicUsize,
     transition_finished: Arc<AtomicBool>,
+    transition_running: AtomicBool,
+    animation_running: AtomicBool,
+    playback: Mutex<PlaybackState>,
+    playback_cvar: Condvar,
+}
+
+/// Set through `swww pause`, `swww resume`, `swww step` and `swww visibility`
+#[derive(Debug, Default)]
+struct PlaybackState {
+    paused: bool,
+    /// How many frames to advance while paused
+    steps: u32,
+    /// Whether we were told the output is not visible. Unlike `paused`, this is a property of the
+    /// output, so it outlives the current animation
+    hidden: bool,
+}
+
+/// What an animation thread should do with its next frame
+pub(super) enum Playback {
+    /// The animation was replaced, so the thread should exit
+    Stop,
+    Play,
+    /// Like `Play`, but we were paused until now, so frame timings must start over
+    Resume,
+    /// Display the frame immediately, since we are paused
+    Step,
 }
 
 #[derive(Debug)]
@@ -41,6 +67,40 @@ impl AnimationToken {
     pub(super) fn set_transition_done(&self, wallpaper: &Wallpaper) {
         if wallpaper.has_animation_id(self) {
             self.transition_done.store(true, Ordering::Release);
+            wallpaper
+                .animation_state
+                .transition_running
+                .store(false, Ordering::Release);
+            crate::events::emit(Event::TransitionFinished {
+                output: wallpaper.name.clone(),
+                img: wallpaper.get_img_info(),
+            });
+        }
+    }
+
+    pub(super) fn set_transition_running(&self, wallpaper: &Wallpaper) {
+        if wallpaper.has_animation_id(self) {
+            wallpaper
+                .animation_state
+                .transition_running
+                .store(true, Ordering::Release);
+        }
+    }
+
+    pub(super) fn set_animation_running(&self, wallpaper: &Wallpaper, running: bool) {
+        if wallpaper.has_animation_id(self)
+            && wallpaper
+                .animation_state
+                .animation_running
+                .swap(running, Ordering::AcqRel)
+                != running
+        {
+            let output = wallpaper.name.clone();
+            crate::events::emit(if running {
+                Event::AnimationStarted { output }
+            } else {
+                Event::AnimationStopped { output }
+            });
         }
     }
 }
@@ -52,25 +112,64 @@ struct FrameCallbackHandler {
     time: Mutex<Option<u32>>,
 }
 
+/// What a wallpaper displays again once it is configured after losing its contents, either
+/// because its output was just connected or because it was resized
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Restore {
+    /// The output's image in the cache, which is the last image it displayed, if any
+    Cache,
+    /// The color it was cleared with, since clears aren't cached
+    Color([u8; 3]),
+}
+
 /// Owns all the necessary information for drawing.
-#[derive(Debug)]
 struct WallpaperInner {
     width: NonZeroI32,
     height: NonZeroI32,
     scale_factor: NonZeroI32,
-
-    pool: BumpPool,
+    /// The scale the compositor prefers for our surface, in 120ths. When we know it, we use it
+    /// instead of `scale_factor`
+    fractional_scale: Option<NonZeroU32>,
+    /// Only informative: we always draw upright, at the output's logical size, and let the
+    /// compositor rotate our buffer
+    transform: wl_output::Transform,
+
+    target: Box<dyn RenderTarget>,
     img: BgImg,
+    /// Unlike `img`, this survives resizes
+    restore: Restore,
+}
+
+impl WallpaperInner {
+    /// The size of our buffers, in pixels
+    fn buffer_size(&self) -> (i32, i32) {
+        match self.fractional_scale {
+            // the protocol tells us to round halfway away from zero
+            Some(scale) => {
+                let scale = scale.get() as i32;
+                (
+                    (self.width.get() * scale + 60) / 120,
+                    (self.height.get() * scale + 60) / 120,
+                )
+            }
+            None => (
+                self.width.get() * self.scale_factor.get(),
+                self.height.get() * self.scale_factor.get(),
+            ),
+        }
+    }
 }
 
 pub(super) struct Wallpaper {
     output_id: u32,
+    name: String,
+    /// Copied from the target, since we look for it on every Wayland event, and the target may be
+    /// locked by an animation in the meantime
+    surface: Option<WlSurface>,
     inner: RwLock<WallpaperInner>,
-    layer_surface: LayerSurface,
 
     animation_state: AnimationState,
     pub configured: AtomicBool,
-    qh: QueueHandle<Daemon>,
     frame_callback_handler: FrameCallbackHandler,
 }
 
@@ -78,62 +177,88 @@ impl Wallpaper {
     pub(crate) fn new(
         output_info: OutputInfo,
         layer_surface: LayerSurface,
+        viewport: Option<Viewport>,
         shm: &Shm,
         qh: &QueueHandle<Daemon>,
     ) -> Self {
-        let (width, height): (NonZeroI32, NonZeroI32) = if let Some(size) = output_info.logical_size
-        {
-            if size.0 == 0 || size.1 == 0 {
-                (256.try_into().unwrap(), 256.try_into().unwrap())
-            } else {
-                (size.0.try_into().unwrap(), size.1.try_into().unwrap())
-            }
-        } else {
-            (256.try_into().unwrap(), 256.try_into().unwrap())
+        let (width, height): (NonZeroI32, NonZeroI32) = match logical_size(&output_info) {
+            Some(size) => (size.0.try_into().unwrap(), size.1.try_into().unwrap()),
+            None => (256.try_into().unwrap(), 256.try_into().unwrap()),
         };
 
         let scale_factor = NonZeroI32::new(output_info.scale_factor).unwrap();
+        let target = WaylandTarget::new(
+            layer_surface,
+            viewport,
+            (width, height),
+            scale_factor,
+            shm,
+            qh,
+        );
+
+        Self::with_target(
+            output_info.id,
+            output_info.name.unwrap_or("?".to_string()),
+            (width, height),
+            scale_factor,
+            output_info.transform,
+            Box::new(target),
+        )
+    }
+
+    /// A wallpaper that draws to memory instead of an output, so that we can test how it draws
+    /// and render previews
+    pub(super) fn headless(name: &str, size: (u32, u32)) -> (Self, crate::render_target::Frames) {
+        let width = NonZeroI32::new(size.0 as i32).unwrap();
+        let height = NonZeroI32::new(size.1 as i32).unwrap();
+        let (target, frames) = crate::render_target::Headless::new((width.get(), height.get()));
+        let wallpaper = Self::with_target(
+            0,
+            name.to_string(),
+            (width, height),
+            NonZeroI32::new(1).unwrap(),
+            wl_output::Transform::Normal,
+            Box::new(target),
+        );
+        (wallpaper, frames)
+    }
 
+    fn with_target(
+        output_id: u32,
+        name: String,
+        (width, height): (NonZeroI32, NonZeroI32),
+        scale_factor: NonZeroI32,
+        transform: wl_output::Transform,
+        target: Box<dyn RenderTarget>,
+    ) -> Self {
         let frame_callback_handler = FrameCallbackHandler {
             cvar: Condvar::new(),
             time: Mutex::new(Some(0)), // we do not have to wait for the first frame
         };
 
-        // Configure the layer surface
-        layer_surface.set_anchor(Anchor::all());
-        layer_surface.set_exclusive_zone(-1);
-        layer_surface.set_margin(0, 0, 0, 0);
-        layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
-        layer_surface.set_size(width.get() as u32, height.get() as u32);
-        layer_surface
-            .set_buffer_scale(scale_factor.get() as u32)
-            .unwrap();
-        // commit so that the compositor send the initial configuration
-        layer_surface.commit();
-        layer_surface
-            .wl_surface()
-            .frame(qh, layer_surface.wl_surface().clone());
-
-        let w = width.get() * scale_factor.get();
-        let h = height.get() * scale_factor.get();
-        let pool = BumpPool::new(w, h, shm, qh);
-
         Self {
-            output_id: output_info.id,
-            layer_surface,
+            output_id,
+            name,
+            surface: target.surface(),
             inner: RwLock::new(WallpaperInner {
                 width,
                 height,
                 scale_factor,
+                fractional_scale: None,
+                transform,
                 img: BgImg::Color([0, 0, 0]),
-                pool,
+                restore: Restore::Cache,
+                target,
             }),
             animation_state: AnimationState {
                 id: AtomicUsize::new(0),
                 transition_finished: Arc::new(AtomicBool::new(false)),
+                transition_running: AtomicBool::new(false),
+                animation_running: AtomicBool::new(false),
+                playback: Mutex::new(PlaybackState::default()),
+                playback_cvar: Condvar::new(),
             },
             configured: AtomicBool::new(false),
-            qh: qh.clone(),
             frame_callback_handler,
         }
     }
@@ -143,6 +268,11 @@ impl Wallpaper {
         self.output_id == id
     }
 
+    #[inline]
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
     #[inline]
     pub(super) fn has_animation_id(&self, token: &AnimationToken) -> bool {
         self.animation_state
@@ -153,15 +283,21 @@ impl Wallpaper {
 
     #[inline]
     pub(super) fn has_surface(&self, surface: &WlSurface) -> bool {
-        self.layer_surface.wl_surface() == surface
+        self.surface.as_ref() == Some(surface)
     }
 
     pub(super) fn get_dimensions(&self) -> (u32, u32) {
-        let inner = self.inner.read().unwrap();
-        let width = inner.width.get() as u32;
-        let height = inner.height.get() as u32;
-        let scale_factor = inner.scale_factor.get() as u32;
-        (width * scale_factor, height * scale_factor)
+        let (width, height) = self.inner.read().unwrap().buffer_size();
+        (width as u32, height as u32)
+    }
+
+    /// The scale we draw at, in 120ths, if it is fractional
+    pub(super) fn get_fractional_scale(&self) -> Option<u32> {
+        self.inner
+            .read()
+            .unwrap()
+            .fractional_scale
+            .map(NonZeroU32::get)
     }
 
     pub(super) fn canvas_change<F, T>(&self, f: F) -> T
@@ -169,7 +305,29 @@ impl Wallpaper {
         F: FnOnce(&mut [u8]) -> T,
     {
         let mut inner = self.inner.write().unwrap();
-        f(inner.pool.get_drawable(&self.qh))
+        f(inner.target.drawable())
+    }
+
+    pub(super) fn get_transform(&self) -> Transform {
+        match self.inner.read().unwrap().transform {
+            wl_output::Transform::_90 => Transform::Rotated90,
+            wl_output::Transform::_180 => Transform::Rotated180,
+            wl_output::Transform::_270 => Transform::Rotated270,
+            wl_output::Transform::Flipped => Transform::Flipped,
+            wl_output::Transform::Flipped90 => Transform::Flipped90,
+            wl_output::Transform::Flipped180 => Transform::Flipped180,
+            wl_output::Transform::Flipped270 => Transform::Flipped270,
+            _ => Transform::Normal,
+        }
+    }
+
+    /// Only for the output's own transform, not the buffer transform the compositor prefers
+    pub(super) fn set_transform(&self, transform: wl_output::Transform) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.transform != transform {
+            log::debug!("output {} - transform: {transform:?}", self.output_id);
+            inner.transform = transform;
+        }
     }
 
     #[inline]
@@ -177,6 +335,20 @@ impl Wallpaper {
         self.inner.read().unwrap().img.clone()
     }
 
+    #[inline]
+    pub(super) fn is_transition_running(&self) -> bool {
+        self.animation_state
+            .transition_running
+            .load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub(super) fn is_animation_running(&self) -> bool {
+        self.animation_state
+            .animation_running
+            .load(Ordering::Acquire)
+    }
+
     #[inline]
     pub(super) fn create_animation_token(&self) -> AnimationToken {
         let id = self.animation_state.id.load(Ordering::Acquire);
@@ -192,13 +364,93 @@ impl Wallpaper {
         self.frame_callback_handler.cvar.notify_all();
     }
 
+    /// Waits up to `timeout` for the compositor to ask for a new frame, returning whether it did.
+    /// Compositors usually stop asking for frames when the surface is not visible.
+    pub(super) fn wait_frame_callback(&self, timeout: Duration) -> bool {
+        let time = self.frame_callback_handler.time.lock().unwrap();
+        let (_time, result) = self
+            .frame_callback_handler
+            .cvar
+            .wait_timeout_while(time, timeout, |time| time.is_none())
+            .unwrap();
+        !result.timed_out()
+    }
+
     /// Stops all animations with the current id, by increasing that id
     #[inline]
     pub(super) fn stop_animations(&self) {
         self.animation_state.id.fetch_add(1, Ordering::AcqRel);
+        // wake up paused animations, so they notice they must stop. New ones start unpaused
+        let mut playback = self.animation_state.playback.lock().unwrap();
+        playback.paused = false;
+        playback.steps = 0;
+        self.animation_state.playback_cvar.notify_all();
+        drop(playback);
         self.animation_state
             .transition_finished
             .store(false, Ordering::Release);
+        self.animation_state
+            .transition_running
+            .store(false, Ordering::Release);
+        if self
+            .animation_state
+            .animation_running
+            .swap(false, Ordering::AcqRel)
+        {
+            crate::events::emit(Event::AnimationStopped {
+                output: self.name.clone(),
+            });
+        }
+    }
+
+    pub(super) fn pause(&self) {
+        self.animation_state.playback.lock().unwrap().paused = true;
+    }
+
+    pub(super) fn resume(&self) {
+        let mut playback = self.animation_state.playback.lock().unwrap();
+        playback.paused = false;
+        playback.steps = 0;
+        self.animation_state.playback_cvar.notify_all();
+    }
+
+    pub(super) fn set_hidden(&self, hidden: bool) {
+        self.animation_state.playback.lock().unwrap().hidden = hidden;
+        self.animation_state.playback_cvar.notify_all();
+    }
+
+    /// Pauses the animation, and advances it by `frames`
+    pub(super) fn step(&self, frames: u32) {
+        let mut playback = self.animation_state.playback.lock().unwrap();
+        playback.paused = true;
+        playback.steps = playback.steps.saturating_add(frames);
+        self.animation_state.playback_cvar.notify_all();
+    }
+
+    /// Blocks the animation thread while its animation is paused
+    pub(super) fn wait_playback(&self, token: &AnimationToken) -> Playback {
+        let mut playback = self.animation_state.playback.lock().unwrap();
+        let mut waited = false;
+        loop {
+            if !self.has_animation_id(token) {
+                return Playback::Stop;
+            }
+            if !playback.hidden {
+                if !playback.paused {
+                    return if waited {
+                        Playback::Resume
+                    } else {
+                        Playback::Play
+                    };
+                }
+                if playback.steps > 0 {
+                    playback.steps -= 1;
+                    return Playback::Step;
+                }
+            }
+            waited = true;
+            playback = self.animation_state.playback_cvar.wait(playback).unwrap();
+        }
     }
 
     pub(super) fn clear(&self, color: [u8; 3]) {
@@ -213,7 +465,17 @@ impl Wallpaper {
 
     pub(super) fn set_img_info(&self, img_info: BgImg) {
         log::debug!("output {} - drawing: {}", self.output_id, img_info);
-        self.inner.write().unwrap().img = img_info;
+        let mut inner = self.inner.write().unwrap();
+        inner.restore = match img_info {
+            BgImg::Color(color) => Restore::Color(color),
+            BgImg::Img(_) => Restore::Cache,
+        };
+        inner.img = img_info;
+    }
+
+    /// What to display once we are configured again
+    pub(super) fn restore(&self) -> Restore {
+        self.inner.read().unwrap().restore
     }
 
     pub(super) fn draw(&self) {
@@ -225,22 +487,12 @@ impl Wallpaper {
             }
             *time = None;
         }
-        let inner = self.inner.read().unwrap();
-        if let Some(buf) = inner.pool.get_commitable_buffer() {
-            let width = inner.width.get() * inner.scale_factor.get();
-            let height = inner.height.get() * inner.scale_factor.get();
-            let surface = self.layer_surface.wl_surface();
-            surface.attach(Some(buf), 0, 0);
-            drop(inner);
-            surface.damage_buffer(0, 0, width, height);
-            surface.commit();
-            surface.frame(&self.qh, surface.clone());
-        } else {
-            drop(inner);
-            // commit and send another frame request, since we consumed the previous one
-            let surface = self.layer_surface.wl_surface();
-            surface.commit();
-            surface.frame(&self.qh, surface.clone());
+        let mut inner = self.inner.write().unwrap();
+        let buffer_size = inner.buffer_size();
+        let frame_callback = inner.target.present(buffer_size);
+        drop(inner);
+        if !frame_callback {
+            self.frame_callback_completed(0);
         }
     }
 
@@ -250,35 +502,186 @@ impl Wallpaper {
         height: Option<NonZeroI32>,
         scale_factor: Option<NonZeroI32>,
     ) {
-        if let Some(s) = scale_factor {
-            self.layer_surface.set_buffer_scale(s.get() as u32).unwrap();
-        }
-        let mut inner = self.inner.write().unwrap();
+        let inner = self.inner.read().unwrap();
         let width = width.unwrap_or(inner.width);
         let height = height.unwrap_or(inner.height);
         let scale_factor = scale_factor.unwrap_or(inner.scale_factor);
-        if (width, height, scale_factor) == (inner.width, inner.height, inner.scale_factor) {
+        let fractional_scale = inner.fractional_scale;
+        drop(inner);
+        self.rescale(width, height, scale_factor, fractional_scale);
+    }
+
+    /// Switches to the scale the compositor prefers for our surface, in 120ths
+    pub(super) fn set_fractional_scale(&self, scale: u32) {
+        let inner = self.inner.read().unwrap();
+        let (width, height, scale_factor) = (inner.width, inner.height, inner.scale_factor);
+        drop(inner);
+        self.rescale(width, height, scale_factor, NonZeroU32::new(scale));
+    }
+
+    fn rescale(
+        &self,
+        width: NonZeroI32,
+        height: NonZeroI32,
+        scale_factor: NonZeroI32,
+        fractional_scale: Option<NonZeroU32>,
+    ) {
+        let mut inner = self.inner.write().unwrap();
+        inner.target.set_scale_factor(scale_factor);
+        let old_size = (inner.width, inner.height, inner.buffer_size());
+        inner.scale_factor = scale_factor;
+        inner.fractional_scale = fractional_scale;
+        inner.width = width;
+        inner.height = height;
+        let (w, h) = inner.buffer_size();
+        if old_size == (width, height, (w, h)) {
             return;
         }
         self.stop_animations();
 
-        inner.width = width;
-        inner.height = height;
-        inner.scale_factor = scale_factor;
         inner.img = BgImg::Color([0, 0, 0]);
-
-        let w = width.get() * scale_factor.get();
-        let h = height.get() * scale_factor.get();
-        inner.pool.resize(w, h, &self.qh);
+        inner.target.resize((width, height), (w, h));
         drop(inner);
 
         *self.frame_callback_handler.time.lock().unwrap() = Some(0);
-        self.layer_surface
-            .set_size(width.get() as u32, height.get() as u32);
-        self.layer_surface.commit();
-        self.layer_surface
-            .wl_surface()
-            .frame(&self.qh, self.layer_surface.wl_surface().clone());
         self.configured.store(false, Ordering::Release);
+        crate::events::emit(Event::OutputResized {
+            output: self.name.clone(),
+            dim: (width.get() as u32, height.get() as u32),
+            scale_factor: scale_factor.get(),
+        });
+    }
+}
+
+/// The output's size in the compositor's logical space, which already accounts for its transform.
+/// Without xdg-output, we derive it from the output's current mode, which does not, so we swap its
+/// width and height when the output is rotated by 90 or 270 degrees
+pub(super) fn logical_size(output_info: &OutputInfo) -> Option<(i32, i32)> {
+    let mode = output_info
+        .modes
+        .iter()
+        .find(|mode| mode.current)
+        .map(|mode| mode.dimensions);
+    size_in_logical_space(
+        output_info.logical_size,
+        mode,
+        output_info.transform,
+        output_info.scale_factor,
+    )
+}
+
+/// [`logical_size`], from the only parts of the output's information it needs
+fn size_in_logical_space(
+    logical_size: Option<(i32, i32)>,
+    mode: Option<(i32, i32)>,
+    transform: wl_output::Transform,
+    scale_factor: i32,
+) -> Option<(i32, i32)> {
+    if let Some((width, height)) = logical_size {
+        if width > 0 && height > 0 {
+            return Some((width, height));
+        }
+    }
+    let mode = mode?;
+    let (width, height) = match transform {
+        wl_output::Transform::_90
+        | wl_output::Transform::_270
+        | wl_output::Transform::Flipped90
+        | wl_output::Transform::Flipped270 => (mode.1, mode.0),
+        _ => mode,
+    };
+    let scale_factor = scale_factor.max(1);
+    let (width, height) = (width / scale_factor, height / scale_factor);
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_modes_swap_their_sides() {
+        use wl_output::Transform;
+        let mode = Some((1920, 1080));
+        for transform in [
+            Transform::Normal,
+            Transform::_180,
+            Transform::Flipped,
+            Transform::Flipped180,
+        ] {
+            let size = size_in_logical_space(None, mode, transform, 1);
+            assert_eq!(size, Some((1920, 1080)), "{transform:?}");
+        }
+        for transform in [
+            Transform::_90,
+            Transform::_270,
+            Transform::Flipped90,
+            Transform::Flipped270,
+        ] {
+            let size = size_in_logical_space(None, mode, transform, 2);
+            assert_eq!(size, Some((540, 960)), "{transform:?}");
+        }
+    }
+
+    #[test]
+    fn logical_sizes_are_already_rotated() {
+        let size = size_in_logical_space(
+            Some((1080, 1920)),
+            Some((1920, 1080)),
+            wl_output::Transform::_90,
+            1,
+        );
+        assert_eq!(size, Some((1080, 1920)));
+        // without a valid logical size or a current mode, there is nothing to go by
+        let size = size_in_logical_space(Some((0, 0)), None, wl_output::Transform::_90, 1);
+        assert_eq!(size, None);
+    }
+
+    #[test]
+    fn clears_are_presented() {
+        let (wallpaper, frames) = Wallpaper::headless("test", (2, 2));
+        // there is nothing to show before we draw anything
+        wallpaper.draw();
+        assert!(frames.take().is_empty());
+
+        wallpaper.clear([1, 2, 3]);
+        wallpaper.draw();
+        let frames = frames.take();
+        assert_eq!(frames.len(), 1);
+        for pixel in frames[0].chunks_exact(4) {
+            assert_eq!(pixel[..3], [3, 2, 1]);
+        }
+    }
+
+    #[test]
+    fn resizing_drops_the_old_contents() {
+        let (wallpaper, frames) = Wallpaper::headless("test", (2, 2));
+        wallpaper.clear([255, 255, 255]);
+        wallpaper.resize(NonZeroI32::new(3), None, NonZeroI32::new(2));
+        assert_eq!(wallpaper.get_dimensions(), (6, 4));
+        wallpaper.draw();
+        assert!(frames.take().is_empty());
+
+        let len = wallpaper.canvas_change(|canvas| {
+            assert!(canvas.iter().all(|&byte| byte == 0));
+            canvas.len()
+        });
+        assert_eq!(len, 6 * 4 * 4);
+    }
+
+    #[test]
+    fn resizing_remembers_clears() {
+        let (wallpaper, _frames) = Wallpaper::headless("test", (2, 2));
+        // new outputs get their image from the cache
+        assert_eq!(wallpaper.restore(), Restore::Cache);
+
+        wallpaper.set_img_info(BgImg::Color([1, 2, 3]));
+        wallpaper.resize(NonZeroI32::new(3), None, None);
+        assert!(matches!(wallpaper.get_img_info(), BgImg::Color([0, 0, 0])));
+        assert_eq!(wallpaper.restore(), Restore::Color([1, 2, 3]));
+
+        wallpaper.set_img_info(BgImg::Img("/a.png".to_string()));
+        wallpaper.resize(NonZeroI32::new(4), None, None);
+        assert_eq!(wallpaper.restore(), Restore::Cache);
     }
 }
@@ -1,7 +1,7 @@
 /// Note: this file only has basic declarations and some definitions in order to be possible to
 /// import it in the build script, to automate shell completion
-use clap::{Parser, ValueEnum};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{path::PathBuf, time::Duration};
 
 fn from_hex(hex: &str) -> Result<[u8; 3], String> {
     let chars = hex
@@ -135,6 +135,20 @@ impl CliPosition {
 ///
 ///Note `swww` will only work in a compositor that implements the layer-shell protocol. Typically,
 ///wlr-roots based compositors.
+pub struct Cli {
+    ///Which daemon to talk to.
+    ///
+    ///Every namespace has its own daemon, with its own socket and cache, so several of them can
+    ///run at the same time (for example, one per nested Wayland session). Without it, we talk to
+    ///the default daemon.
+    #[arg(long, global = true, env = "SWWW_SOCKET")]
+    pub namespace: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Swww,
+}
+
+#[derive(Subcommand)]
 pub enum Swww {
     ///Fills the specified outputs with the given color.
     ///
@@ -147,9 +161,18 @@ pub enum Swww {
     ///Clears the swww cache.
     ///
     ///We currently store the address of the last file set as wallpaper for each monitor, as well
-    ///as the animation frames of every gif ever set for a given version of `swww`.
+    ///as the animation frames of the most recently used animated images. See `swww cache` for
+    ///finer grained control.
     ClearCache,
 
+    ///Inspects and manages the cache of animation frames.
+    ///
+    ///The cache is limited to 512MiB by default, which can be changed with the `SWWW_CACHE_SIZE`
+    ///environment variable (eg: `SWWW_CACHE_SIZE=2G`). The least recently used animations are
+    ///evicted first.
+    #[command(subcommand)]
+    Cache(Cache),
+
     /// Sends an image (or animated gif) for the daemon to display.
     ///
     /// Use `-` to read from stdin
@@ -157,8 +180,8 @@ pub enum Swww {
 
     /// Initializes the daemon.
     ///
-    /// Exits if there is already a daemon running. We check that by seeing if
-    /// $XDG_RUNTIME_DIR/swww.socket exists.
+    /// Exits if there is already a daemon running in the same namespace. We check that by trying
+    /// to connect to its socket, $XDG_RUNTIME_DIR/swww.socket (or swww-<NAMESPACE>.socket).
     Init {
         ///Don't fork the daemon. This will keep it running in the current terminal.
         ///
@@ -173,6 +196,37 @@ pub enum Swww {
         ///results some reliable: `swww init --no-cache && swww img <some img>`
         #[clap(long)]
         no_cache: bool,
+
+        ///Which layer-shell layer to draw the wallpapers on.
+        ///
+        ///Use `bottom` with compositors that draw something of their own on the background layer,
+        ///so that the wallpapers end up above it.
+        #[clap(long, default_value = "background")]
+        layer: Layer,
+
+        ///The layer-shell namespace of the daemon's surfaces.
+        ///
+        ///Compositors can match on this in their rules, for example to blur or animate the
+        ///wallpapers differently.
+        #[clap(long, default_value = "swww")]
+        layer_namespace: String,
+
+        ///The most detailed kind of message the daemon logs.
+        ///
+        ///Release builds never log debug messages.
+        #[clap(long, default_value = "debug")]
+        log_level: LogLevel,
+
+        ///Makes the daemon append its logs to this file.
+        ///
+        ///Otherwise, they are lost once the daemon forks (unless you pass `--syslog`, or
+        ///`--no-daemon`, which keeps them in the terminal).
+        #[clap(long, conflicts_with = "syslog")]
+        log_file: Option<PathBuf>,
+
+        ///Makes the daemon send its logs to the system log (`/dev/log`), which journald also reads.
+        #[clap(long)]
+        syslog: bool,
     },
 
     ///Kills the daemon
@@ -182,7 +236,195 @@ pub enum Swww {
     ///
     ///You may use this to find out valid values for the <swww-img --outputs> option. If you want
     ///more detailed information about your outputs, I would recommend trying wlr-randr.
-    Query,
+    Query(Query),
+
+    ///Prints events from the daemon as they happen, one JSON object per line.
+    ///
+    ///Events are sent when outputs are added, removed or resized, when transitions finish, and
+    ///when animations start or stop. See `man swww-watch` for the format of each event.
+    Watch,
+
+    ///Freezes animated wallpapers on their current frame.
+    ///
+    ///They stay paused until `swww resume`, or until another image is set.
+    Pause(Playback),
+
+    ///Resumes paused animated wallpapers.
+    Resume(Playback),
+
+    ///Pauses animated wallpapers and advances them by some frames.
+    Step(Step),
+
+    ///Tells the daemon whether outputs are visible.
+    ///
+    ///Animations on hidden outputs are paused until they are visible again, to save power. This is
+    ///meant to be called from idle daemons or compositor scripts, eg: when a fullscreen window
+    ///covers the wallpaper. It is independent from `swww pause`.
+    Visibility(Visibility),
+
+    ///Cycles through images, switching them every so often.
+    ///
+    ///The daemon runs the playlist by itself, and resumes it where it left off when restarted.
+    ///Setting an image or clearing an output stops its playlist.
+    Playlist(Playlist),
+
+    ///Switches to the next image in the playlists.
+    Next(PlaylistSwitch),
+
+    ///Switches to the previous image in the playlists.
+    Prev(PlaylistSwitch),
+
+    ///Manages the schedule the daemon switches images by.
+    ///
+    ///The schedule is read from `$SWWW_SCHEDULE`, or `$XDG_CONFIG_HOME/swww/schedule` if it is not
+    ///set. See swww-schedule(1) for its format.
+    #[command(subcommand)]
+    Schedule(Schedule),
+
+    ///Renders a transition between two images to an animated file.
+    ///
+    ///This doesn't need a running daemon, and is meant for trying transition settings out. The
+    ///frames are drawn by `swww-daemon --preview`, with the same code it uses for wallpapers.
+    Preview(Preview),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Layer {
+    Background,
+    Bottom,
+}
+
+impl Layer {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Background => "background",
+            Self::Bottom => "bottom",
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum PlaylistOrder {
+    /// Sorted by path
+    Sorted,
+    /// Shuffled once, when the playlist starts
+    Random,
+}
+
+#[derive(Parser)]
+pub struct Playlist {
+    /// Images to display. Directories are expanded into the images they contain
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Comma separated list of outputs to run the playlist at.
+    ///
+    /// If it isn't set, the playlist runs on all outputs.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+
+    /// How long to display each image.
+    ///
+    /// Accepts 's', 'm' and 'h' suffixes (eg: 90s, 5m, 1.5h). Plain numbers are seconds
+    #[arg(short, long, default_value = "5m", value_parser = parse_interval)]
+    pub interval: Duration,
+
+    /// The order to display the images in
+    #[arg(long, default_value = "sorted")]
+    pub order: PlaylistOrder,
+
+    /// Whether to resize the images and the method by which to resize them. See `swww img --help`
+    #[arg(long, default_value = "crop")]
+    pub resize: ResizeStrategy,
+
+    /// Which color to fill the padding with when the images do not fill the screen
+    #[arg(value_parser = from_hex, long, default_value = "000000")]
+    pub fill_color: [u8; 3],
+
+    /// Filter to use when scaling images. See `swww img --help`
+    #[arg(short, long, default_value = "Lanczos3")]
+    pub filter: Filter,
+
+    #[command(flatten)]
+    pub transition: TransitionArgs,
+}
+
+#[derive(Parser)]
+pub struct PlaylistSwitch {
+    /// Comma separated list of outputs whose playlists to switch.
+    ///
+    /// If it isn't set, all playlists are switched.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum VisibilityState {
+    Visible,
+    Hidden,
+}
+
+#[derive(Parser)]
+pub struct Visibility {
+    pub state: VisibilityState,
+
+    /// Comma separated list of outputs whose visibility changed.
+    ///
+    /// If it isn't set, all outputs are affected.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+}
+
+#[derive(Parser)]
+pub struct Playback {
+    /// Comma separated list of outputs whose animations to control.
+    ///
+    /// If it isn't set, all outputs are affected.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+}
+
+#[derive(Parser)]
+pub struct Step {
+    /// How many frames to advance
+    #[arg(short, long, default_value = "1")]
+    pub frames: u32,
+
+    /// Comma separated list of outputs whose animations to control.
+    ///
+    /// If it isn't set, all outputs are affected.
+    #[arg(short, long, default_value = "")]
+    pub outputs: String,
+}
+
+#[derive(Parser)]
+pub struct Query {
+    /// Print the information as JSON, for use in scripts.
+    ///
+    /// See `man swww-query` for the schema.
+    #[arg(short, long)]
+    pub json: bool,
 }
 
 #[derive(Parser)]
@@ -215,6 +457,37 @@ pub enum ResizeStrategy {
     Fit,
 }
 
+#[derive(Subcommand)]
+pub enum Cache {
+    ///Lists the cached animations, most recently used first.
+    List,
+
+    ///Prints how much space the cache is using.
+    Stats,
+
+    ///Removes animations whose image changed or no longer exists, and then the least recently
+    ///used ones until the cache fits in its size limit.
+    Prune,
+
+    ///Removes all cached frames of the given image.
+    Remove {
+        /// Path to the image whose frames should be removed
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum Schedule {
+    ///Makes the daemon read the schedule again, after it was edited.
+    Reload,
+
+    ///Checks whether a schedule is valid, without involving the daemon.
+    Check {
+        /// Path to the schedule. Defaults to the one the daemon would read
+        file: Option<PathBuf>,
+    },
+}
+
 #[derive(Parser)]
 pub struct Restore {
     /// Comma separated list of outputs to restore.
@@ -222,18 +495,31 @@ pub struct Restore {
     /// If it isn't set, all outputs will be restored.
     #[arg(short, long, default_value = "")]
     pub outputs: String,
+
+    /// Replay the transition the images were originally set with.
+    ///
+    /// By default, restored images are displayed immediately.
+    #[arg(long)]
+    pub transition: bool,
 }
 
 #[derive(Parser)]
 pub struct Img {
-    /// Path to the image to display
-    pub path: PathBuf,
+    /// Path to the image to display.
+    ///
+    /// It may be omitted if every output gets its own image through `--outputs`.
+    #[arg(required_unless_present = "outputs")]
+    pub path: Option<PathBuf>,
 
     /// Comma separated list of outputs to display the image at.
     ///
-    /// If it isn't set, the image is displayed on all outputs.
-    #[arg(short, long, default_value = "")]
-    pub outputs: String,
+    /// If it isn't set, the image is displayed on all outputs. It may be given more than once,
+    /// and may also assign an image to a single output, as `OUTPUT=PATH` (eg:
+    /// `-o DP-1=a.png -o HDMI-A-1=b.jpg`). All images are sent in the same request, so their
+    /// transitions start together. Outputs without an image of their own display the one in
+    /// `PATH`.
+    #[arg(short, long)]
+    pub outputs: Vec<String>,
 
     /// Do not resize the image. Equivalent to `--resize=no`
     ///
@@ -273,6 +559,140 @@ pub struct Img {
     #[arg(short, long, default_value = "Lanczos3")]
     pub filter: Filter,
 
+    /// Playback speed of animations, as a multiple of their original speed
+    ///
+    /// eg: 2 plays them twice as fast, 0.5 at half speed
+    #[arg(long, default_value = "1", value_parser = parse_speed)]
+    pub speed: f32,
+
+    /// How many times to play animations before stopping on their last frame. 0 loops forever
+    #[arg(long, default_value = "0")]
+    pub loops: u32,
+
+    /// Play animations backwards
+    #[arg(long, conflicts_with = "ping_pong")]
+    pub reverse: bool,
+
+    /// Play animations forward and then backwards, instead of restarting them
+    #[arg(long)]
+    pub ping_pong: bool,
+
+    /// Span the image across the outputs, as if they were a single screen.
+    ///
+    /// The outputs are laid out according to their positions in the compositor, and the image is
+    /// resized (see `--resize`) to cover all of them, including the gaps between them. Outputs
+    /// with different scale factors get the same part of the image they would at the largest
+    /// one. Animated images only display their first frame.
+    #[arg(long)]
+    pub span: bool,
+
+    #[command(flatten)]
+    pub transition: TransitionArgs,
+}
+
+impl Img {
+    /// Which image goes to which outputs, according to `path` and `outputs`. An empty list of
+    /// outputs stands for every output that doesn't have an image of its own
+    pub fn targets(&self) -> Result<Vec<(PathBuf, Vec<String>)>, String> {
+        let mut outputs = Vec::new();
+        let mut targets: Vec<(PathBuf, Vec<String>)> = Vec::new();
+        let mut assigned: Vec<&str> = Vec::new();
+        for value in &self.outputs {
+            match value.split_once('=') {
+                Some((output, path)) => {
+                    if output.is_empty() || path.is_empty() {
+                        return Err(format!("expected OUTPUT=PATH, found: {value}"));
+                    }
+                    if assigned.contains(&output) {
+                        return Err(format!("output {output} was given more than one image"));
+                    }
+                    assigned.push(output);
+                    let path = PathBuf::from(path);
+                    match targets.iter_mut().find(|(p, _)| *p == path) {
+                        Some((_, outputs)) => outputs.push(output.to_string()),
+                        None => targets.push((path, vec![output.to_string()])),
+                    }
+                }
+                None => outputs.extend(
+                    value
+                        .split(',')
+                        .filter(|o| !o.is_empty())
+                        .map(|o| o.to_string()),
+                ),
+            }
+        }
+
+        match &self.path {
+            Some(path) => targets.push((path.clone(), outputs)),
+            None if !outputs.is_empty() => {
+                return Err(format!(
+                    "no image to display at outputs {}",
+                    outputs.join(",")
+                ))
+            }
+            None if targets.is_empty() => return Err("missing image path".to_string()),
+            None => (),
+        }
+        Ok(targets)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum PreviewFormat {
+    Gif,
+    Apng,
+}
+
+#[derive(Parser)]
+pub struct Preview {
+    /// The image the transition starts from
+    #[arg(long)]
+    pub from: PathBuf,
+
+    /// The image the transition ends on
+    #[arg(long)]
+    pub to: PathBuf,
+
+    /// Where to write the preview.
+    ///
+    /// Its format comes from the extension (`.gif`, or `.png` and `.apng` for animated png),
+    /// unless `--format` is given.
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// The format to write the preview in, regardless of the extension of `--output`
+    #[arg(long)]
+    pub format: Option<PreviewFormat>,
+
+    /// The size to render the preview at, as WIDTHxHEIGHT. Both images are resized to it.
+    ///
+    /// Frames are rendered at `--transition-fps`.
+    #[arg(long, default_value = "640x360", value_parser = parse_size)]
+    pub size: (u32, u32),
+
+    /// How long to show the last frame before the preview loops, in seconds
+    #[arg(long, default_value = "1", value_parser = parse_hold)]
+    pub hold: Duration,
+
+    /// Whether to resize the images and the method by which to resize them
+    #[arg(long, default_value = "crop")]
+    pub resize: ResizeStrategy,
+
+    /// Which color to fill the padding with when the images do not fill the preview
+    #[arg(value_parser = from_hex, long, default_value = "000000")]
+    pub fill_color: [u8; 3],
+
+    /// Filter to use when scaling images. See `swww img --help` for the options
+    #[arg(short, long, default_value = "Lanczos3")]
+    pub filter: Filter,
+
+    #[command(flatten)]
+    pub transition: TransitionArgs,
+}
+
+/// Options controlling how the image changes
+#[derive(Args)]
+pub struct TransitionArgs {
     ///Sets the type of transition. Default is 'simple', that fades into the new image
     ///
     ///Possible transitions are:
@@ -370,6 +790,57 @@ pub struct Img {
     pub transition_wave: (f32, f32),
 }
 
+fn parse_interval(raw: &str) -> Result<Duration, String> {
+    let raw = raw.trim();
+    let (number, unit) = match raw.chars().last() {
+        Some('s') => (&raw[..raw.len() - 1], 1.0),
+        Some('m') => (&raw[..raw.len() - 1], 60.0),
+        Some('h') => (&raw[..raw.len() - 1], 3600.0),
+        _ => (raw, 1.0),
+    };
+    let secs = number.trim().parse::<f64>().map_err(|e| e.to_string())? * unit;
+    if secs.is_nan() || secs <= 0.0 {
+        return Err("the interval must be positive".to_string());
+    }
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+fn parse_size(raw: &str) -> Result<(u32, u32), String> {
+    let (width, height) = raw
+        .split_once(['x', 'X'])
+        .ok_or_else(|| "expected WIDTHxHEIGHT".to_string())?;
+    let parse = |n: &str| match n.trim().parse::<u32>() {
+        Ok(0) => Err("the size can't be zero".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_hold(raw: &str) -> Result<Duration, String> {
+    let secs = raw.trim().parse::<f64>().map_err(|e| e.to_string())?;
+    if secs.is_nan() || secs < 0.0 {
+        return Err("the hold can't be negative".to_string());
+    }
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+/// The animation speeds the daemon accepts (`AnimationPlayback::SPEEDS`). This file is also
+/// included by the build script, which can't use `utils`, so we repeat them here
+const SPEEDS: std::ops::RangeInclusive<f32> = 0.01..=100.0;
+
+fn parse_speed(raw: &str) -> Result<f32, String> {
+    match raw.parse::<f32>() {
+        Ok(speed) if SPEEDS.contains(&speed) => Ok(speed),
+        Ok(_) => Err(format!(
+            "speed must be between {} and {}",
+            SPEEDS.start(),
+            SPEEDS.end()
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 fn parse_wave(raw: &str) -> Result<(f32, f32), String> {
     let mut iter = raw.split(',');
     let mut parse = || {
@@ -502,6 +973,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn should_accept_namespace_anywhere() {
+        for args in [
+            ["swww", "--namespace", "nested", "kill"],
+            ["swww", "kill", "--namespace", "nested"],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.namespace.as_deref(), Some("nested"));
+        }
+    }
+
+    #[test]
+    fn should_keep_the_layer_namespace_apart_from_the_namespace() {
+        let cli = Cli::try_parse_from(["swww", "--namespace", "nested", "init"]).unwrap();
+        assert_eq!(cli.namespace.as_deref(), Some("nested"));
+        match cli.command {
+            Swww::Init {
+                layer_namespace, ..
+            } => assert_eq!(layer_namespace, "swww"),
+            _ => panic!("expected init"),
+        }
+    }
+
     #[test]
     fn should_convert_colors_from_hex() {
         let color = from_hex("101010").unwrap();
@@ -513,4 +1007,67 @@ mod tests {
         let color = from_hex("000000").unwrap();
         assert_eq!(color, [0, 0, 0]);
     }
+
+    #[test]
+    fn should_assign_images_to_outputs() {
+        let targets = |args: &[&str]| {
+            Img::try_parse_from([&["img"], args].concat())
+                .map_err(|e| e.to_string())
+                .and_then(|img| img.targets())
+        };
+        assert_eq!(
+            targets(&["a.png", "-o", "DP-1,DP-2"]).unwrap(),
+            [(PathBuf::from("a.png"), vec!["DP-1".into(), "DP-2".into()])]
+        );
+        assert_eq!(
+            targets(&["-o", "DP-1=a.png", "-o", "DP-2=b.png", "-o", "DP-3=a.png"]).unwrap(),
+            [
+                (PathBuf::from("a.png"), vec!["DP-1".into(), "DP-3".into()]),
+                (PathBuf::from("b.png"), vec!["DP-2".into()])
+            ]
+        );
+        assert_eq!(
+            targets(&["c.png", "-o", "DP-1=a.png"]).unwrap(),
+            [
+                (PathBuf::from("a.png"), vec!["DP-1".into()]),
+                (PathBuf::from("c.png"), vec![])
+            ]
+        );
+        assert!(targets(&[]).is_err());
+        assert!(targets(&["-o", "DP-1"]).is_err());
+        assert!(targets(&["-o", "DP-1=a.png", "-o", "DP-2"]).is_err());
+        assert!(targets(&["-o", "DP-1=a.png", "-o", "DP-1=b.png"]).is_err());
+        assert!(targets(&["-o", "=a.png"]).is_err());
+    }
+
+    #[test]
+    fn should_parse_intervals() {
+        assert_eq!(parse_interval("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_interval("1.5h").unwrap(), Duration::from_secs(5400));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("-1m").is_err());
+        assert!(parse_interval("soon").is_err());
+    }
+
+    #[test]
+    fn should_bound_speeds() {
+        assert_eq!(SPEEDS, utils::ipc::AnimationPlayback::SPEEDS);
+        assert_eq!(parse_speed("0.5").unwrap(), 0.5);
+        assert_eq!(parse_speed("100").unwrap(), 100.0);
+        assert!(parse_speed("1e-30").is_err());
+        assert!(parse_speed("1000").is_err());
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("NaN").is_err());
+    }
+
+    #[test]
+    fn should_parse_sizes() {
+        assert_eq!(parse_size("640x360").unwrap(), (640, 360));
+        assert_eq!(parse_size("1920X1080").unwrap(), (1920, 1080));
+        assert!(parse_size("640").is_err());
+        assert!(parse_size("0x360").is_err());
+        assert!(parse_size("640x-1").is_err());
+    }
 }
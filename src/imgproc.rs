@@ -1,19 +1,19 @@
-use fast_image_resize::{FilterType, PixelType, Resizer};
+use fast_image_resize::FilterType;
 use image::{
     codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
-    AnimationDecoder, DynamicImage, Frames, ImageFormat, RgbImage,
+    AnimationDecoder, DynamicImage, Frame, Frames, ImageFormat, ImageResult, RgbImage,
 };
 use std::{
     fs::File,
     io::Stdin,
     io::{stdin, BufReader, Read},
-    num::NonZeroU32,
     path::Path,
     time::Duration,
 };
 
 use utils::{
     compression::{BitPack, Compressor},
+    imgproc::{img_pad, img_resize_crop, img_resize_fit},
     ipc::{self, Coord, Position},
 };
 
@@ -144,13 +144,15 @@ pub fn frame_to_rgb(frame: image::Frame) -> RgbImage {
     DynamicImage::ImageRgba8(frame.into_buffer()).into_rgb8()
 }
 
+/// Compresses `frames` as the difference from each one to the next. Also returns whether the last
+/// frame goes back to the first one, which it doesn't when they are identical
 pub fn compress_frames(
-    mut frames: Frames,
+    mut frames: impl Iterator<Item = ImageResult<Frame>>,
     dim: (u32, u32),
     filter: FilterType,
     resize: ResizeStrategy,
     color: &[u8; 3],
-) -> Result<Vec<(BitPack, Duration)>, String> {
+) -> Result<(Vec<(BitPack, Duration)>, bool), String> {
     let mut compressor = Compressor::new();
     let mut compressed_frames = Vec::new();
 
@@ -193,9 +195,13 @@ pub fn compress_frames(
     }
 
     //Add the first frame we got earlier:
+    let mut wraps_around = false;
     if let Some(canvas) = canvas.as_ref() {
         match compressor.compress(canvas, &first_img) {
-            Some(bytes) => compressed_frames.push((bytes, first_duration)),
+            Some(bytes) => {
+                compressed_frames.push((bytes, first_duration));
+                wraps_around = true;
+            }
             None => match compressed_frames.last_mut() {
                 Some(last) => last.1 += first_duration,
                 None => first_duration += first_duration,
@@ -203,7 +209,7 @@ pub fn compress_frames(
         }
     }
 
-    Ok(compressed_frames)
+    Ok((compressed_frames, wraps_around))
 }
 
 pub fn make_filter(filter: &cli::Filter) -> fast_image_resize::FilterType {
@@ -216,185 +222,7 @@ pub fn make_filter(filter: &cli::Filter) -> fast_image_resize::FilterType {
     }
 }
 
-pub fn img_pad(
-    mut img: RgbImage,
-    dimensions: (u32, u32),
-    color: &[u8; 3],
-) -> Result<Vec<u8>, String> {
-    let (padded_w, padded_h) = dimensions;
-    let (padded_w, padded_h) = (padded_w as usize, padded_h as usize);
-    let mut padded = Vec::with_capacity(padded_h * padded_w * 3);
-
-    let img = {
-        if img.width() > dimensions.0 || img.height() > dimensions.1 {
-            let left = (img.width() - dimensions.0) / 2;
-            let top = (img.height() - dimensions.1) / 2;
-            image::imageops::crop(&mut img, left, top, dimensions.0, dimensions.1).to_image()
-        } else {
-            image::imageops::crop(&mut img, 0, 0, dimensions.0, dimensions.1).to_image()
-        }
-    };
-    let (img_w, img_h) = img.dimensions();
-    let (img_w, img_h) = (img_w as usize, img_h as usize);
-    let raw_img = img.into_vec();
-
-    for _ in 0..(((padded_h - img_h) / 2) * padded_w) {
-        padded.push(color[2]);
-        padded.push(color[1]);
-        padded.push(color[0]);
-    }
-
-    // Calculate left and right border widths. `u32::div` rounds toward 0, so, if `img_w` is odd,
-    // add an extra pixel to the right border to ensure the row is the correct width.
-    let left_border_w = (padded_w - img_w) / 2;
-    let right_border_w = left_border_w + (img_w % 2);
-
-    for row in 0..img_h {
-        for _ in 0..left_border_w {
-            padded.push(color[2]);
-            padded.push(color[1]);
-            padded.push(color[0]);
-        }
-
-        for pixel in raw_img[(row * img_w * 3)..((row + 1) * img_w * 3)].chunks_exact(3) {
-            padded.push(pixel[2]);
-            padded.push(pixel[1]);
-            padded.push(pixel[0]);
-        }
-        for _ in 0..right_border_w {
-            padded.push(color[2]);
-            padded.push(color[1]);
-            padded.push(color[0]);
-        }
-    }
-
-    while padded.len() < (padded_h * padded_w * 3) {
-        padded.push(color[2]);
-        padded.push(color[1]);
-        padded.push(color[0]);
-    }
-
-    Ok(padded)
-}
-
-/// Convert an RGB &[u8] to BRG in-place by swapping bytes
-#[inline]
-fn rgb_to_brg(rgb: &mut [u8]) {
-    for pixel in rgb.chunks_exact_mut(3) {
-        pixel.swap(0, 2);
-    }
-}
-
-/// Resize an image to fit within the given dimensions, covering as much space as possible without
-/// cropping.
-pub fn img_resize_fit(
-    img: RgbImage,
-    dimensions: (u32, u32),
-    filter: FilterType,
-    padding_color: &[u8; 3],
-) -> Result<Vec<u8>, String> {
-    let (width, height) = dimensions;
-    let (img_w, img_h) = img.dimensions();
-    if (img_w, img_h) != (width, height) {
-        // if our image is already scaled to fit, skip resizing it and just pad it directly
-        if img_w == width || img_h == height {
-            return img_pad(img, dimensions, padding_color);
-        }
-
-        let ratio = width as f32 / height as f32;
-        let img_r = img_w as f32 / img_h as f32;
-
-        let (trg_w, trg_h) = if ratio > img_r {
-            let scale = height as f32 / img_h as f32;
-            ((img_w as f32 * scale) as u32, height)
-        } else {
-            let scale = width as f32 / img_w as f32;
-            (width, (img_h as f32 * scale) as u32)
-        };
-
-        let src = match fast_image_resize::Image::from_vec_u8(
-            // We unwrap below because we know the images's dimensions should never be 0
-            NonZeroU32::new(img_w).unwrap(),
-            NonZeroU32::new(img_h).unwrap(),
-            img.into_raw(),
-            PixelType::U8x3,
-        ) {
-            Ok(i) => i,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        // We unwrap below because we know the outputs's dimensions should never be 0
-        let new_w = NonZeroU32::new(trg_w).unwrap();
-        let new_h = NonZeroU32::new(trg_h).unwrap();
-
-        let mut dst = fast_image_resize::Image::new(new_w, new_h, PixelType::U8x3);
-        let mut dst_view = dst.view_mut();
-
-        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
-        if let Err(e) = resizer.resize(&src.view(), &mut dst_view) {
-            return Err(e.to_string());
-        }
-
-        img_pad(
-            image::RgbImage::from_raw(trg_w, trg_h, dst.into_vec()).unwrap(),
-            dimensions,
-            padding_color,
-        )
-    } else {
-        let mut res = img.into_vec();
-        // The ARGB is 'little endian', so here we must  put the order
-        // of bytes 'in reverse', so it needs to be BGRA.
-        rgb_to_brg(&mut res);
-        Ok(res)
-    }
-}
-
-pub fn img_resize_crop(
-    img: RgbImage,
-    dimensions: (u32, u32),
-    filter: FilterType,
-) -> Result<Vec<u8>, String> {
-    let (width, height) = dimensions;
-    let (img_w, img_h) = img.dimensions();
-    let mut resized_img = if (img_w, img_h) != (width, height) {
-        let src = match fast_image_resize::Image::from_vec_u8(
-            // We unwrap below because we know the images's dimensions should never be 0
-            NonZeroU32::new(img_w).unwrap(),
-            NonZeroU32::new(img_h).unwrap(),
-            img.into_raw(),
-            PixelType::U8x3,
-        ) {
-            Ok(i) => i,
-            Err(e) => return Err(e.to_string()),
-        };
-
-        // We unwrap below because we know the outputs's dimensions should never be 0
-        let new_w = NonZeroU32::new(width).unwrap();
-        let new_h = NonZeroU32::new(height).unwrap();
-        let mut src_view = src.view();
-        src_view.set_crop_box_to_fit_dst_size(new_w, new_h, Some((0.5, 0.5)));
-
-        let mut dst = fast_image_resize::Image::new(new_w, new_h, PixelType::U8x3);
-        let mut dst_view = dst.view_mut();
-
-        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
-        if let Err(e) = resizer.resize(&src_view, &mut dst_view) {
-            return Err(e.to_string());
-        }
-
-        dst.into_vec()
-    } else {
-        img.into_vec()
-    };
-
-    // The ARGB is 'little endian', so here we must  put the order
-    // of bytes 'in reverse', so it needs to be BGRA.
-    rgb_to_brg(&mut resized_img);
-
-    Ok(resized_img)
-}
-
-pub fn make_transition(img: &cli::Img) -> ipc::Transition {
+pub fn make_transition(img: &cli::TransitionArgs) -> ipc::Transition {
     let mut angle = img.transition_angle;
     let mut step = img.transition_step;
 
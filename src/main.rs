@@ -1,49 +1,78 @@
 use clap::Parser;
-use std::{os::unix::net::UnixStream, path::PathBuf, process::Stdio, time::Duration};
+use rand::seq::SliceRandom;
+use std::{
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
 
 use utils::{
     cache,
-    ipc::{self, get_socket_path, read_socket, AnimationRequest, Answer, ArchivedAnswer, Request},
+    imgproc::{crop_span, img_pad, img_resize_crop, img_resize_fit, resize_span, FrameOrder, Span},
+    ipc::{
+        self, get_socket_path, read_socket, AnimationRequest, Answer, ArchivedAnswer, Capabilities,
+        Event, Request,
+    },
+    schedule::{sun_times, LocalTime, Schedule},
 };
 
 mod imgproc;
 use imgproc::*;
 
 mod cli;
-use cli::{ResizeStrategy, Swww};
+use cli::{Cli, ResizeStrategy, Swww};
+
+mod preview;
 
 fn main() -> Result<(), String> {
-    let swww = Swww::parse();
-    if let Swww::Init { no_daemon, .. } = &swww {
-        match is_daemon_running() {
-            Ok(false) => {
-                let socket_path = get_socket_path();
-                if socket_path.exists() {
-                    eprintln!(
-                        "WARNING: socket file {} was not deleted when the previous daemon exited",
-                        socket_path.to_string_lossy()
-                    );
-                    if let Err(e) = std::fs::remove_file(socket_path) {
-                        return Err(format!("failed to delete previous socket: {e}"));
-                    }
-                }
-            }
-            Ok(true) => {
-                return Err("There seems to already be another instance running...".to_string())
-            }
-            Err(e) => {
-                eprintln!("WARNING: failed to read '/proc' directory to determine whether the daemon is running: {e}
-                          Falling back to trying to checking if the socket file exists...");
-                let socket_path = get_socket_path();
-                if socket_path.exists() {
-                    return Err(format!(
-                        "Found socket at {}. There seems to be an instance already running...",
-                        socket_path.to_string_lossy()
-                    ));
-                }
+    let cli = Cli::parse();
+    ipc::set_namespace(cli.namespace.clone())?;
+    let swww = cli.command;
+    if let Swww::Init {
+        no_daemon,
+        layer,
+        layer_namespace,
+        log_level,
+        log_file,
+        syslog,
+        ..
+    } = &swww
+    {
+        let socket_path = get_socket_path();
+        if is_daemon_running(&socket_path)? {
+            return Err(format!(
+                "There is already a daemon listening at {}",
+                socket_path.to_string_lossy()
+            ));
+        }
+        if socket_path.exists() {
+            eprintln!(
+                "WARNING: socket file {} was not deleted when the previous daemon exited",
+                socket_path.to_string_lossy()
+            );
+            if let Err(e) = std::fs::remove_file(socket_path) {
+                return Err(format!("failed to delete previous socket: {e}"));
             }
         }
-        spawn_daemon(*no_daemon)?;
+        let mut args = vec![
+            "--layer".into(),
+            layer.as_str().into(),
+            "--layer-namespace".into(),
+            layer_namespace.into(),
+            "--log-level".into(),
+            log_level.as_str().into(),
+        ];
+        if let Some(namespace) = &cli.namespace {
+            args.extend(["--namespace".into(), namespace.into()]);
+        }
+        if let Some(log_file) = log_file {
+            args.extend(["--log-file".into(), log_file.into()]);
+        }
+        if *syslog {
+            args.push("--syslog".into());
+        }
+        spawn_daemon(*no_daemon, &args)?;
         if *no_daemon {
             return Ok(());
         }
@@ -53,12 +82,24 @@ fn main() -> Result<(), String> {
         return cache::clean();
     }
 
+    if let Swww::Cache(command) = &swww {
+        return process_cache_command(command);
+    }
+
+    if let Swww::Schedule(cli::Schedule::Check { file }) = &swww {
+        return check_schedule(file.as_deref());
+    }
+
+    if let Swww::Preview(preview) = &swww {
+        return preview::render(preview);
+    }
+
     let mut configured = false;
     while !configured {
         let socket = connect_to_socket(5, 100)?;
         Request::Ping.send(&socket)?;
         let bytes = read_socket(&socket)?;
-        let answer = Answer::receive(&bytes);
+        let answer = Answer::receive(&bytes)?;
         if let ArchivedAnswer::Ping(c) = answer {
             configured = *c;
         } else {
@@ -81,26 +122,41 @@ fn process_swww_args(args: &Swww) -> Result<(), String> {
     request.send(&socket)?;
     let bytes = read_socket(&socket)?;
     drop(socket);
-    match Answer::receive(&bytes) {
+    match Answer::receive(&bytes)? {
         ArchivedAnswer::Err(msg) => return Err(msg.to_string()),
-        ArchivedAnswer::Info(info) => info.iter().for_each(|i| println!("{}", i)),
+        ArchivedAnswer::Malformed(e) => return Err(format!("daemon rejected the request: {e}")),
+        ArchivedAnswer::Info(info) => {
+            if let Swww::Query(cli::Query { json: true }) = args {
+                let outputs: Vec<String> = info.iter().map(|i| i.to_json()).collect();
+                println!(r#"{{"outputs":[{}]}}"#, outputs.join(","));
+            } else {
+                info.iter().for_each(|i| println!("{}", i))
+            }
+        }
         ArchivedAnswer::Ok => {
-            if let Swww::Kill = args {
-                #[cfg(debug_assertions)]
-                let tries = 20;
-                #[cfg(not(debug_assertions))]
-                let tries = 10;
-                let socket_path = get_socket_path();
-                for _ in 0..tries {
-                    if !socket_path.exists() {
-                        return Ok(());
-                    }
-                    std::thread::sleep(Duration::from_millis(100));
+            if let Swww::Img(img) = args {
+                store_cache(img, &request, &[]);
+            }
+        }
+        ArchivedAnswer::Killed { removes_socket } => {
+            // a socket that belongs to systemd stays, so there is nothing to wait for
+            if !removes_socket {
+                return Ok(());
+            }
+            #[cfg(debug_assertions)]
+            let tries = 20;
+            #[cfg(not(debug_assertions))]
+            let tries = 10;
+            let socket_path = get_socket_path();
+            for _ in 0..tries {
+                if !socket_path.exists() {
+                    return Ok(());
                 }
-                return Err(format!(
-                    "Could not confirm socket deletion at: {socket_path:?}"
-                ));
+                std::thread::sleep(Duration::from_millis(100));
             }
+            return Err(format!(
+                "Could not confirm socket deletion at: {socket_path:?}"
+            ));
         }
         ArchivedAnswer::Ping(_) => {
             return Ok(());
@@ -117,64 +173,236 @@ fn make_request(args: &Swww) -> Result<Option<Request>, String> {
         }))),
         Swww::Restore(restore) => {
             let requested_outputs = split_cmdline_outputs(&restore.outputs);
-            restore_from_cache(&requested_outputs)?;
+            restore_from_cache(&requested_outputs, restore.transition)?;
             Ok(None)
         }
         Swww::ClearCache => unreachable!("there is no request for clear-cache"),
+        Swww::Cache(_) => unreachable!("there is no request for cache"),
+        Swww::Preview(_) => unreachable!("there is no request for preview"),
         Swww::Img(img) => {
-            let requested_outputs = split_cmdline_outputs(&img.outputs);
-            let (dims, outputs) = get_dimensions_and_outputs(&requested_outputs)?;
-            let imgbuf = ImgBuf::new(&img.path)?;
-            if imgbuf.is_animated() {
-                match std::thread::scope::<_, Result<_, String>>(|s1| {
-                    let animations = s1.spawn(|| make_animation_request(img, &dims, &outputs));
-                    let first_frame = imgbuf
-                        .into_frames()?
-                        .next()
-                        .ok_or("missing first frame".to_owned())?
-                        .map_err(|e| format!("unable to decode first frame: {e}"))?;
-
-                    let img_request =
-                        make_img_request(img, frame_to_rgb(first_frame), &dims, &outputs)?;
-                    let animations = animations.join().unwrap_or_else(|e| Err(format!("{e:?}")));
-
-                    let socket = connect_to_socket(5, 100)?;
-                    Request::Img(img_request).send(&socket)?;
-                    let bytes = read_socket(&socket)?;
-                    drop(socket);
-                    if let ArchivedAnswer::Err(e) = Answer::receive(&bytes) {
-                        return Err(format!("daemon error when sending image: {e}"));
-                    }
-                    animations
-                }) {
-                    Ok(animations) => Ok(Some(Request::Animation(animations))),
-                    Err(e) => Err(format!("failed to create animated request: {e}")),
+            let targets = img.targets()?;
+            if img.span {
+                let (path, requested_outputs) = match &targets[..] {
+                    [target] => target,
+                    _ => return Err("--span only works with a single image".to_string()),
+                };
+                let imgbuf = ImgBuf::new(path)?;
+                if imgbuf.is_animated() {
+                    eprintln!("WARNING: spanned images only display their first frame");
                 }
-            } else {
-                let img_raw = imgbuf.decode()?;
-                Ok(Some(Request::Img(make_img_request(
-                    img, img_raw, &dims, &outputs,
-                )?)))
+                let (request, spans) =
+                    make_span_request(img, path, imgbuf.decode()?, requested_outputs)?;
+                let request = Request::Img(request);
+                send_request(&request)?;
+                store_cache(img, &request, &spans);
+                return Ok(None);
             }
+
+            std::thread::scope::<_, Result<_, String>>(|s1| {
+                let mut images = Vec::new();
+                let mut animations = Vec::new();
+                for (path, requested_outputs) in &targets {
+                    let (dims, outputs) = target_outputs(requested_outputs, &targets)?;
+                    if dims.is_empty() {
+                        continue;
+                    }
+                    let imgbuf = ImgBuf::new(path)?;
+                    let img_raw = if imgbuf.is_animated() {
+                        let (dims, outputs) = (dims.clone(), outputs.clone());
+                        animations.push(
+                            s1.spawn(move || make_animation_request(img, path, &dims, &outputs)),
+                        );
+                        let first_frame = imgbuf
+                            .into_frames()?
+                            .next()
+                            .ok_or("missing first frame".to_owned())?
+                            .map_err(|e| format!("unable to decode first frame: {e}"))?;
+                        frame_to_rgb(first_frame)
+                    } else {
+                        imgbuf.decode()?
+                    };
+                    let (_, requests) = make_img_request(img, path, img_raw, &dims, &outputs)?;
+                    images.extend(requests.into_vec());
+                }
+                let request =
+                    Request::Img((make_transition(&img.transition), images.into_boxed_slice()));
+                if animations.is_empty() {
+                    return Ok(request);
+                }
+
+                let socket = connect_to_socket(5, 100)?;
+                request.send(&socket)?;
+                let bytes = read_socket(&socket)?;
+                drop(socket);
+                match Answer::receive(&bytes)? {
+                    ArchivedAnswer::Err(e) => {
+                        return Err(format!("daemon error when sending image: {e}"))
+                    }
+                    ArchivedAnswer::Malformed(e) => {
+                        return Err(format!("daemon rejected the image: {e}"))
+                    }
+                    _ => store_cache(img, &request, &[]),
+                }
+
+                let mut requests = Vec::new();
+                for animation in animations {
+                    let (_, animations) = animation
+                        .join()
+                        .unwrap_or_else(|e| Err(format!("{e:?}")))
+                        .map_err(|e| format!("failed to create animated request: {e}"))?;
+                    requests.extend(animations.into_vec());
+                }
+                let playback = ipc::AnimationPlayback {
+                    speed: img.speed,
+                    loops: img.loops,
+                };
+                Ok(Request::Animation((playback, requests.into_boxed_slice())))
+            })
+            .map(Some)
         }
         Swww::Init { no_cache, .. } => {
             if !*no_cache {
-                restore_from_cache(&[])?;
+                restore_from_cache(&[], false)?;
             }
-            Ok(None)
+            Ok(Some(Request::Restored))
         }
         Swww::Kill => Ok(Some(Request::Kill)),
-        Swww::Query => Ok(Some(Request::Query)),
+        Swww::Query(_) => Ok(Some(Request::Query)),
+        Swww::Watch => {
+            watch()?;
+            Ok(None)
+        }
+        Swww::Pause(pause) => Ok(Some(Request::Pause(split_cmdline_outputs(&pause.outputs)))),
+        Swww::Resume(resume) => Ok(Some(Request::Resume(split_cmdline_outputs(
+            &resume.outputs,
+        )))),
+        Swww::Step(step) => Ok(Some(Request::Step(ipc::Step {
+            frames: step.frames,
+            outputs: split_cmdline_outputs(&step.outputs),
+        }))),
+        Swww::Visibility(visibility) => Ok(Some(Request::Visibility(ipc::Visibility {
+            visible: matches!(visibility.state, cli::VisibilityState::Visible),
+            outputs: split_cmdline_outputs(&visibility.outputs),
+        }))),
+        Swww::Playlist(playlist) => Ok(Some(Request::Playlist(ipc::Playlist {
+            paths: playlist_paths(&playlist.paths, &playlist.order)?,
+            position: 0,
+            interval: playlist.interval,
+            resize: resize_strategy(&playlist.resize),
+            filter: cache_filter(&playlist.filter),
+            fill_color: playlist.fill_color,
+            transition: make_transition(&playlist.transition),
+            immediate: false,
+            outputs: split_cmdline_outputs(&playlist.outputs),
+        }))),
+        Swww::Next(next) => Ok(Some(Request::Next(split_cmdline_outputs(&next.outputs)))),
+        Swww::Prev(prev) => Ok(Some(Request::Prev(split_cmdline_outputs(&prev.outputs)))),
+        Swww::Schedule(cli::Schedule::Reload) => Ok(Some(Request::ReloadSchedule)),
+        Swww::Schedule(cli::Schedule::Check { .. }) => {
+            unreachable!("checking the schedule does not involve the daemon")
+        }
+    }
+}
+
+fn process_cache_command(command: &cli::Cache) -> Result<(), String> {
+    match command {
+        cli::Cache::List => {
+            for entry in cache::entries()? {
+                let path = entry.path.as_deref().unwrap_or("<unknown image>");
+                println!("{path}: {}, {}", entry.details, human_size(entry.size));
+            }
+        }
+        cli::Cache::Stats => {
+            let entries = cache::entries()?;
+            let total = entries.iter().map(|e| e.size).sum();
+            println!("cached animations: {}", entries.len());
+            println!("total size: {}", human_size(total));
+            println!("size limit: {}", human_size(cache::max_cache_size()));
+        }
+        cli::Cache::Prune => {
+            let (count, freed) = cache::prune()?;
+            println!(
+                "removed {count} cached animations, freeing {}",
+                human_size(freed)
+            );
+        }
+        cli::Cache::Remove { path } => {
+            if cache::remove(path)? == 0 {
+                return Err(format!("there are no cached frames for {path:?}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_schedule(file: Option<&std::path::Path>) -> Result<(), String> {
+    let path = match file {
+        Some(file) => file.to_path_buf(),
+        None => Schedule::default_path()?,
+    };
+    let schedule = Schedule::load(&path)?;
+    println!("{}: {} entries", path.display(), schedule.entries.len());
+    if let Some((latitude, longitude)) = schedule.location {
+        let now = LocalTime::now()?;
+        let (sunrise, sunset) = sun_times(latitude, longitude, now.day_of_year, now.utc_offset);
+        println!(
+            "today's sunrise: {:02}:{:02}, sunset: {:02}:{:02}",
+            sunrise / 60,
+            sunrise % 60,
+            sunset / 60,
+            sunset % 60
+        );
+    }
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{size:.1}{}", UNITS[unit])
+    }
+}
+
+fn watch() -> Result<(), String> {
+    let (socket, capabilities) = connect_with_capabilities(5, 100)?;
+    if !capabilities.contains(Capabilities::SUBSCRIBE) {
+        return Err("the daemon does not support subscribing to events".to_string());
+    }
+    Request::Subscribe.send(&socket)?;
+    let bytes = read_socket(&socket)?;
+    match Answer::receive(&bytes)? {
+        ArchivedAnswer::Ok => (),
+        ArchivedAnswer::Err(e) => return Err(format!("daemon error when subscribing: {e}")),
+        ArchivedAnswer::Malformed(e) => return Err(format!("daemon rejected the request: {e}")),
+        _ => return Err("daemon did not return Answer::Ok, as expected".to_string()),
+    }
+
+    // events may take arbitrarily long to arrive
+    if let Err(e) = socket.set_read_timeout(None) {
+        return Err(format!("failed to unset read timeout for socket: {e}"));
+    }
+    loop {
+        let bytes = read_socket(&socket).map_err(|e| format!("lost connection to daemon: {e}"))?;
+        println!("{}", Event::receive(&bytes)?.to_json());
     }
 }
 
 fn make_img_request(
     img: &cli::Img,
+    path: &Path,
     img_raw: image::RgbImage,
     dims: &[(u32, u32)],
     outputs: &[Vec<String>],
 ) -> Result<ipc::ImageRequest, String> {
-    let transition = make_transition(img);
+    let transition = make_transition(&img.transition);
     let mut unique_requests = Vec::with_capacity(dims.len());
     for (dim, outputs) in dims.iter().zip(outputs) {
         unique_requests.push((
@@ -192,10 +420,10 @@ fn make_img_request(
                     )?,
                 }
                 .into_boxed_slice(),
-                path: match img.path.canonicalize() {
+                path: match path.canonicalize() {
                     Ok(p) => p.to_string_lossy().to_string(),
                     Err(e) => {
-                        if let Some("-") = img.path.to_str() {
+                        if let Some("-") = path.to_str() {
                             "STDIN".to_string()
                         } else {
                             return Err(format!("failed no canonicalize image path: {e}"));
@@ -218,83 +446,232 @@ fn get_dimensions_and_outputs(
     let mut dims: Vec<(u32, u32)> = Vec::new();
     let mut imgs: Vec<ipc::BgImg> = Vec::new();
 
+    query_outputs(requested_outputs, |info| {
+        let info_img = info.img.de();
+        let name = info.name.to_string();
+        let real_dim = info.real_dim();
+        if let Some((_, output)) = dims
+            .iter_mut()
+            .zip(&imgs)
+            .zip(&mut outputs)
+            .find(|((dim, img), _)| real_dim == **dim && info_img == **img)
+        {
+            output.push(name);
+        } else {
+            outputs.push(vec![name]);
+            dims.push(real_dim);
+            imgs.push(info_img);
+        }
+    })?;
+    Ok((dims, outputs))
+}
+
+/// Like [`get_dimensions_and_outputs`], for one of the targets of `swww img`. An empty list of
+/// outputs stands for the ones no other target has
+#[allow(clippy::type_complexity)]
+fn target_outputs(
+    requested_outputs: &[String],
+    targets: &[(PathBuf, Vec<String>)],
+) -> Result<(Vec<(u32, u32)>, Vec<Vec<String>>), String> {
+    let (dims, outputs) = get_dimensions_and_outputs(requested_outputs)?;
+    if !requested_outputs.is_empty() {
+        return Ok((dims, outputs));
+    }
+    let assigned: Vec<&String> = targets.iter().flat_map(|(_, outputs)| outputs).collect();
+    Ok(dims
+        .into_iter()
+        .zip(outputs)
+        .filter_map(|(dim, mut outputs)| {
+            outputs.retain(|output| !assigned.contains(&output));
+            (!outputs.is_empty()).then_some((dim, outputs))
+        })
+        .unzip())
+}
+
+/// Calls `f` with the information of each of the requested outputs (or all of them, if empty)
+fn query_outputs(
+    requested_outputs: &[String],
+    mut f: impl FnMut(&ipc::ArchivedBgInfo),
+) -> Result<(), String> {
     let socket = connect_to_socket(5, 100)?;
     Request::Query.send(&socket)?;
     let bytes = read_socket(&socket)?;
     drop(socket);
-    let answer = Answer::receive(&bytes);
+    let answer = Answer::receive(&bytes)?;
     match answer {
         ArchivedAnswer::Info(infos) => {
+            let mut found = false;
             for info in infos.iter() {
-                let info_img = info.img.de();
-                let name = info.name.to_string();
-                if !requested_outputs.is_empty() && !requested_outputs.contains(&name) {
-                    continue;
-                }
-                let real_dim = (
-                    info.dim.0 * info.scale_factor as u32,
-                    info.dim.1 * info.scale_factor as u32,
-                );
-                if let Some((_, output)) = dims
-                    .iter_mut()
-                    .zip(&imgs)
-                    .zip(&mut outputs)
-                    .find(|((dim, img), _)| real_dim == **dim && info_img == **img)
+                if requested_outputs.is_empty()
+                    || requested_outputs.iter().any(|o| o == info.name.as_str())
                 {
-                    output.push(name);
-                } else {
-                    outputs.push(vec![name]);
-                    dims.push(real_dim);
-                    imgs.push(info_img);
+                    found = true;
+                    f(info);
                 }
             }
-            if outputs.is_empty() {
-                Err("none of the requested outputs are valid".to_owned())
+            if found {
+                Ok(())
             } else {
-                Ok((dims, outputs))
+                Err("none of the requested outputs are valid".to_owned())
             }
         }
         ArchivedAnswer::Err(e) => Err(format!("daemon error when sending query: {e}")),
+        ArchivedAnswer::Malformed(e) => Err(format!("daemon rejected the query: {e}")),
         _ => unreachable!(),
     }
 }
 
+/// Makes a request that displays `img_raw` across `requested_outputs`, along with each output's
+/// part of it, for the cache
+fn make_span_request(
+    img: &cli::Img,
+    path: &Path,
+    img_raw: image::RgbImage,
+    requested_outputs: &[String],
+) -> Result<(ipc::ImageRequest, Vec<(String, Span)>), String> {
+    let mut outputs = Vec::new();
+    let mut layout = Vec::new();
+    query_outputs(requested_outputs, |info| {
+        outputs.push((info.name.to_string(), info.real_dim()));
+        layout.push((
+            (info.position.0, info.position.1),
+            (info.dim.0, info.dim.1),
+            info.scale_factor,
+        ));
+    })?;
+    let spans = Span::layout(&layout);
+    let canvas = spans[0].canvas;
+    if canvas.0 == 0 || canvas.1 == 0 {
+        return Err("the outputs have no size yet".to_string());
+    }
+
+    let filter = cache_filter(&img.filter);
+    let canvas = utils::imgproc::resize(
+        img_raw,
+        canvas,
+        resize_strategy(&img.resize),
+        filter,
+        &img.fill_color,
+    )?;
+    let path = match path.canonicalize() {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(_) if path.to_str() == Some("-") => "STDIN".to_string(),
+        Err(e) => return Err(format!("failed no canonicalize image path: {e}")),
+    };
+    let mut requests = Vec::with_capacity(outputs.len());
+    for ((output, dim), span) in outputs.iter().zip(&spans) {
+        requests.push((
+            ipc::Img {
+                img: crop_span(&canvas, span, *dim, filter)?.into_boxed_slice(),
+                path: path.clone(),
+            },
+            Box::from([output.clone()]),
+        ));
+    }
+    let transition = make_transition(&img.transition);
+    let spans = outputs.into_iter().map(|(output, _)| output).zip(spans);
+    Ok(((transition, requests.into_boxed_slice()), spans.collect()))
+}
+
 fn make_animation_request(
     img: &cli::Img,
+    img_path: &Path,
     dims: &[(u32, u32)],
     outputs: &[Vec<String>],
 ) -> Result<AnimationRequest, String> {
     let filter = make_filter(&img.filter);
+    // use the same path as the image request, so that the daemon can find the frames when
+    // restoring the image
+    let path = img_path
+        .canonicalize()
+        .unwrap_or_else(|_| img_path.to_path_buf());
+    let order = frame_order(img);
     let mut animations = Vec::with_capacity(dims.len());
     for (dim, outputs) in dims.iter().zip(outputs) {
-        //TODO: make cache work for all resize strategies
-        if img.resize == ResizeStrategy::Crop {
-            match cache::load_animation_frames(&img.path, *dim) {
-                Ok(Some(animation)) => {
-                    animations.push((animation, outputs.to_owned().into_boxed_slice()));
-                    continue;
-                }
-                Ok(None) => (),
-                Err(e) => eprintln!("Error loading cache for {:?}: {e}", img.path),
+        match cache::load_animation_frames(
+            &path,
+            *dim,
+            resize_strategy(&img.resize),
+            cache_filter(&img.filter),
+            img.fill_color,
+            order,
+        ) {
+            Ok(Some(animation)) => {
+                animations.push((animation, outputs.to_owned().into_boxed_slice()));
+                continue;
             }
+            Ok(None) => (),
+            Err(e) => eprintln!("Error loading cache for {img_path:?}: {e}"),
         }
 
-        let imgbuf = ImgBuf::new(&img.path)?;
+        let frames = ImgBuf::new(img_path)?.into_frames()?;
+        let (frames, wraps_around) = match order {
+            FrameOrder::Forward => {
+                compress_frames(frames, *dim, filter, img.resize, &img.fill_color)?
+            }
+            // we need all frames in memory to reorder them
+            FrameOrder::Reverse | FrameOrder::PingPong => {
+                let frames = frames
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("failed to decode frame: {e}"))?;
+                let frames = order.apply(frames).into_iter().map(Ok);
+                compress_frames(frames, *dim, filter, img.resize, &img.fill_color)?
+            }
+        };
         let animation = ipc::Animation {
-            path: img.path.to_string_lossy().to_string(),
+            path: path.to_string_lossy().to_string(),
             dimensions: *dim,
-            animation: compress_frames(
-                imgbuf.into_frames()?,
-                *dim,
-                filter,
-                img.resize,
-                &img.fill_color,
-            )?
-            .into_boxed_slice(),
+            animation: frames.into_boxed_slice(),
+            wraps_around,
         };
         animations.push((animation, outputs.to_owned().into_boxed_slice()));
     }
-    Ok(animations.into_boxed_slice())
+    let playback = ipc::AnimationPlayback {
+        speed: img.speed,
+        loops: img.loops,
+    };
+    Ok((playback, animations.into_boxed_slice()))
+}
+
+fn frame_order(img: &cli::Img) -> FrameOrder {
+    if img.reverse {
+        FrameOrder::Reverse
+    } else if img.ping_pong {
+        FrameOrder::PingPong
+    } else {
+        FrameOrder::Forward
+    }
+}
+
+/// Expands the directories in `paths` into the images they contain, and puts them in `order`
+fn playlist_paths(paths: &[PathBuf], order: &cli::PlaylistOrder) -> Result<Box<[String]>, String> {
+    let mut images = Vec::new();
+    for path in paths {
+        let path = path
+            .canonicalize()
+            .map_err(|e| format!("failed to find {path:?}: {e}"))?;
+        if path.is_dir() {
+            let read_dir = path
+                .read_dir()
+                .map_err(|e| format!("failed to read directory {path:?}: {e}"))?;
+            for entry in read_dir.flatten() {
+                let path = entry.path();
+                if path.is_file() && image::ImageFormat::from_path(&path).is_ok() {
+                    images.push(path.to_string_lossy().to_string());
+                }
+            }
+        } else {
+            images.push(path.to_string_lossy().to_string());
+        }
+    }
+    if images.is_empty() {
+        return Err("there are no images to put in the playlist".to_string());
+    }
+    match order {
+        cli::PlaylistOrder::Sorted => images.sort(),
+        cli::PlaylistOrder::Random => images.shuffle(&mut rand::thread_rng()),
+    }
+    Ok(images.into_boxed_slice())
 }
 
 fn split_cmdline_outputs(outputs: &str) -> Box<[String]> {
@@ -305,8 +682,9 @@ fn split_cmdline_outputs(outputs: &str) -> Box<[String]> {
         .collect()
 }
 
-fn spawn_daemon(no_daemon: bool) -> Result<(), String> {
+fn spawn_daemon(no_daemon: bool, args: &[std::ffi::OsString]) -> Result<(), String> {
     let mut cmd = std::process::Command::new("swww-daemon");
+    cmd.args(args);
     if no_daemon {
         match cmd.status() {
             Ok(_) => Ok(()),
@@ -320,11 +698,20 @@ fn spawn_daemon(no_daemon: bool) -> Result<(), String> {
     }
 }
 
-/// We make sure the Stream is always set to blocking mode
+/// We make sure the Stream is always set to blocking mode, and that the daemon speaks the same
+/// protocol version as we do
 ///
 /// * `tries` -  how make times to attempt the connection
 /// * `interval` - how long to wait between attempts, in milliseconds
 fn connect_to_socket(tries: u8, interval: u64) -> Result<UnixStream, String> {
+    connect_with_capabilities(tries, interval).map(|(socket, _)| socket)
+}
+
+/// Same as `connect_to_socket`, but also returns the capabilities we share with the daemon
+fn connect_with_capabilities(
+    tries: u8,
+    interval: u64,
+) -> Result<(UnixStream, Capabilities), String> {
     //Make sure we try at least once
     let tries = if tries == 0 { 1 } else { tries };
     let path = get_socket_path();
@@ -344,7 +731,8 @@ fn connect_to_socket(tries: u8, interval: u64) -> Result<UnixStream, String> {
                     return Err(format!("failed to set read timeout for socket: {e}"));
                 }
 
-                return Ok(socket);
+                let capabilities = ipc::client_handshake(&socket)?;
+                return Ok((socket, capabilities));
             }
             Err(e) => error = Some(e),
         }
@@ -358,65 +746,234 @@ fn connect_to_socket(tries: u8, interval: u64) -> Result<UnixStream, String> {
     Err(format!("Failed to connect to socket: {error}"))
 }
 
-fn is_daemon_running() -> Result<bool, String> {
-    let proc = PathBuf::from("/proc");
+/// A daemon is running if something accepts connections on its socket. A socket file nobody listens
+/// on was left behind by a daemon that didn't exit cleanly. We close the connection without
+/// sending anything, which the daemon knows to ignore
+fn is_daemon_running(socket_path: &Path) -> Result<bool, String> {
+    match UnixStream::connect(socket_path) {
+        Ok(_) => Ok(true),
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused => Ok(false),
+            _ => Err(format!(
+                "failed to check whether a daemon is listening at {}: {e}",
+                socket_path.to_string_lossy()
+            )),
+        },
+    }
+}
 
-    let entries = match proc.read_dir() {
-        Ok(e) => e,
-        Err(e) => return Err(e.to_string()),
-    };
+fn restore_from_cache(requested_outputs: &[String], with_transition: bool) -> Result<(), String> {
+    let (dims, outputs) = get_dimensions_and_outputs(requested_outputs)?;
+    let outputs = dims
+        .iter()
+        .zip(&outputs)
+        .flat_map(|(dim, outputs)| outputs.iter().map(move |output| (output, *dim)));
 
-    for entry in entries.flatten() {
-        let dirname = entry.file_name();
-        if let Ok(pid) = dirname.to_string_lossy().parse::<u32>() {
-            if std::process::id() == pid {
-                continue;
+    for (output, dim) in outputs {
+        let cached = match cache::get_previous_image(output)? {
+            Some(cached) => cached,
+            None => continue,
+        };
+        if let Some(playlist) = &cached.playlist {
+            // the daemon takes care of displaying the playlist's current image
+            let request = Request::Playlist(ipc::Playlist {
+                paths: playlist.paths.clone().into_boxed_slice(),
+                position: playlist.position as u32,
+                interval: playlist.interval,
+                resize: cached.resize,
+                filter: cached.filter,
+                fill_color: cached.fill_color,
+                transition: cached.transition.clone().unwrap_or_else(instant_transition),
+                immediate: !with_transition,
+                outputs: Box::new([output.to_string()]),
+            });
+            if let Err(e) = send_request(&request) {
+                eprintln!("WARNING: failed to restore the playlist of output {output}: {e}");
             }
-            let mut entry_path = entry.path();
-            entry_path.push("cmdline");
-            if let Ok(cmd) = std::fs::read_to_string(entry_path) {
-                let mut args = cmd.split(&[' ', '\0']);
-                if let Some(arg0) = args.next() {
-                    if arg0.ends_with("swww-daemon") {
-                        return Ok(true);
-                    }
-                }
+            continue;
+        }
+        if let Some(span) = &cached.span {
+            let transition = match &cached.transition {
+                Some(transition) if with_transition => transition.clone(),
+                _ => instant_transition(),
+            };
+            let request = utils::imgproc::decode(Path::new(&cached.path)).and_then(|img| {
+                let img = resize_span(
+                    img,
+                    span,
+                    dim,
+                    cached.resize,
+                    cached.filter,
+                    &cached.fill_color,
+                )?;
+                let img = ipc::Img {
+                    img: img.into_boxed_slice(),
+                    path: cached.path.clone(),
+                };
+                send_request(&Request::Img((
+                    transition,
+                    Box::new([(img, Box::from([output.clone()]))]),
+                )))
+            });
+            if let Err(e) = request {
+                eprintln!("WARNING: failed to load cache for output {output}: {e}");
             }
+            continue;
         }
-    }
-
-    Ok(false)
-}
 
-fn restore_from_cache(requested_outputs: &[String]) -> Result<(), String> {
-    let (_, outputs) = get_dimensions_and_outputs(requested_outputs)?;
-
-    for output in outputs.iter().flatten() {
-        let img_path = utils::cache::get_previous_image_path(output)?;
+        let transition = cached.transition.as_ref().filter(|_| with_transition);
         #[allow(deprecated)]
         if let Err(e) = process_swww_args(&Swww::Img(cli::Img {
-            path: PathBuf::from(img_path),
-            outputs: output.to_string(),
+            path: Some(PathBuf::from(&cached.path)),
+            outputs: vec![output.to_string()],
             no_resize: false,
-            resize: ResizeStrategy::Crop,
-            fill_color: [0, 0, 0],
-            filter: cli::Filter::Lanczos3,
-            transition_type: cli::TransitionType::None,
-            transition_step: u8::MAX,
-            transition_duration: 0.0,
-            transition_fps: 30,
-            transition_angle: 0.0,
-            transition_pos: cli::CliPosition {
-                x: cli::CliCoord::Pixel(0.0),
-                y: cli::CliCoord::Pixel(0.0),
+            resize: match cached.resize {
+                utils::imgproc::ResizeStrategy::No => ResizeStrategy::No,
+                utils::imgproc::ResizeStrategy::Crop => ResizeStrategy::Crop,
+                utils::imgproc::ResizeStrategy::Fit => ResizeStrategy::Fit,
+            },
+            fill_color: cached.fill_color,
+            filter: match cached.filter {
+                utils::imgproc::Filter::Nearest => cli::Filter::Nearest,
+                utils::imgproc::Filter::Bilinear => cli::Filter::Bilinear,
+                utils::imgproc::Filter::CatmullRom => cli::Filter::CatmullRom,
+                utils::imgproc::Filter::Mitchell => cli::Filter::Mitchell,
+                utils::imgproc::Filter::Lanczos3 => cli::Filter::Lanczos3,
             },
-            invert_y: false,
-            transition_bezier: (0.0, 0.0, 0.0, 0.0),
-            transition_wave: (0.0, 0.0),
+            transition: cli::TransitionArgs {
+                transition_type: match transition.map(|t| &t.transition_type) {
+                    None => cli::TransitionType::None,
+                    Some(ipc::TransitionType::Simple) => cli::TransitionType::Simple,
+                    Some(ipc::TransitionType::Fade) => cli::TransitionType::Fade,
+                    Some(ipc::TransitionType::Outer) => cli::TransitionType::Outer,
+                    Some(ipc::TransitionType::Wipe) => cli::TransitionType::Wipe,
+                    Some(ipc::TransitionType::Grow) => cli::TransitionType::Grow,
+                    Some(ipc::TransitionType::Wave) => cli::TransitionType::Wave,
+                },
+                transition_step: transition.map_or(u8::MAX, |t| t.step),
+                transition_duration: transition.map_or(0.0, |t| t.duration),
+                transition_fps: transition.map_or(30, |t| t.fps),
+                transition_angle: transition.map_or(0.0, |t| t.angle),
+                transition_pos: match transition {
+                    Some(t) => cli::CliPosition::new(cli_coord(&t.pos.x), cli_coord(&t.pos.y)),
+                    None => {
+                        cli::CliPosition::new(cli::CliCoord::Pixel(0.0), cli::CliCoord::Pixel(0.0))
+                    }
+                },
+                invert_y: transition.is_some_and(|t| t.invert_y),
+                transition_bezier: transition.map_or((0.0, 0.0, 0.0, 0.0), |t| t.bezier),
+                transition_wave: transition.map_or((0.0, 0.0), |t| t.wave),
+            },
+            speed: cached.speed,
+            loops: cached.loops,
+            reverse: cached.order == FrameOrder::Reverse,
+            ping_pong: cached.order == FrameOrder::PingPong,
+            span: false,
         })) {
             eprintln!("WARNING: failed to load cache for output {output}: {e}");
+            continue;
+        }
+        // setting the image overwrote the cache entry, possibly without its transition
+        if let Err(e) = cache::store(output, &cached) {
+            eprintln!("ERROR: failed to store cache: {e}");
         }
     }
 
     Ok(())
 }
+
+/// A transition that displays the new image right away
+fn instant_transition() -> ipc::Transition {
+    ipc::Transition {
+        transition_type: ipc::TransitionType::Simple,
+        duration: 0.0,
+        step: u8::MAX,
+        fps: 30,
+        angle: 0.0,
+        pos: ipc::Position::new(ipc::Coord::Pixel(0.0), ipc::Coord::Pixel(0.0)),
+        bezier: (0.0, 0.0, 0.0, 0.0),
+        wave: (0.0, 0.0),
+        invert_y: false,
+    }
+}
+
+/// Sends `request` on its own connection, returning the daemon's error if it has one
+fn send_request(request: &Request) -> Result<(), String> {
+    let socket = connect_to_socket(5, 100)?;
+    request.send(&socket)?;
+    let bytes = read_socket(&socket)?;
+    match Answer::receive(&bytes)? {
+        ArchivedAnswer::Err(e) => Err(e.to_string()),
+        ArchivedAnswer::Malformed(e) => Err(format!("daemon rejected the request: {e}")),
+        _ => Ok(()),
+    }
+}
+
+fn cli_coord(coord: &ipc::Coord) -> cli::CliCoord {
+    match coord {
+        ipc::Coord::Pixel(x) => cli::CliCoord::Pixel(*x),
+        ipc::Coord::Percent(x) => cli::CliCoord::Percent(*x),
+    }
+}
+
+/// Registers the image in `request` as the last one displayed on its outputs, so that it can be
+/// restored later with the exact same parameters. For animations, we store their frames instead
+fn store_cache(img: &cli::Img, request: &Request, spans: &[(String, Span)]) {
+    let resize = resize_strategy(&img.resize);
+    let filter = cache_filter(&img.filter);
+    match request {
+        Request::Img((transition, imgs)) => {
+            for (ipc::Img { path, .. }, outputs) in imgs.iter() {
+                for output in outputs.iter() {
+                    let cached = cache::CachedImg {
+                        path: path.clone(),
+                        resize,
+                        filter,
+                        fill_color: img.fill_color,
+                        transition: Some(transition.clone()),
+                        order: frame_order(img),
+                        speed: img.speed,
+                        loops: img.loops,
+                        playlist: None,
+                        span: spans.iter().find(|(o, _)| o == output).map(|(_, s)| *s),
+                    };
+                    if let Err(e) = cache::store(output, &cached) {
+                        eprintln!("ERROR: failed to store cache: {e}");
+                    }
+                }
+            }
+        }
+        Request::Animation((_, animations)) => {
+            for (animation, _) in animations.iter() {
+                if let Err(e) = cache::store_animation_frames(
+                    animation,
+                    resize,
+                    filter,
+                    img.fill_color,
+                    frame_order(img),
+                ) {
+                    eprintln!("Error storing cache for {}: {e}", animation.path);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn resize_strategy(resize: &ResizeStrategy) -> utils::imgproc::ResizeStrategy {
+    match resize {
+        ResizeStrategy::No => utils::imgproc::ResizeStrategy::No,
+        ResizeStrategy::Crop => utils::imgproc::ResizeStrategy::Crop,
+        ResizeStrategy::Fit => utils::imgproc::ResizeStrategy::Fit,
+    }
+}
+
+fn cache_filter(filter: &cli::Filter) -> utils::imgproc::Filter {
+    match filter {
+        cli::Filter::Nearest => utils::imgproc::Filter::Nearest,
+        cli::Filter::Bilinear => utils::imgproc::Filter::Bilinear,
+        cli::Filter::CatmullRom => utils::imgproc::Filter::CatmullRom,
+        cli::Filter::Mitchell => utils::imgproc::Filter::Mitchell,
+        cli::Filter::Lanczos3 => utils::imgproc::Filter::Lanczos3,
+    }
+}
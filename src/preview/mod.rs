@@ -0,0 +1,179 @@
+//! `swww preview`: renders a transition to an animated file, without a running daemon.
+//!
+//! The frames come from `swww-daemon --preview`, so they are drawn by the same code the daemon
+//! uses to change wallpapers
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame, RgbaImage,
+};
+use utils::{
+    imgproc::{img_pad, img_resize_crop, img_resize_fit},
+    ipc,
+};
+
+use crate::{
+    cli::{self, PreviewFormat, ResizeStrategy},
+    imgproc::{make_filter, make_transition, ImgBuf},
+};
+
+pub fn render(args: &cli::Preview) -> Result<(), String> {
+    let format = match args.format {
+        Some(format) => format,
+        None => format_of(&args.output)?,
+    };
+    let transition = make_transition(&args.transition);
+    if transition.fps == 0 {
+        return Err("the transition fps must be positive".to_string());
+    }
+    let frame_time = Duration::from_secs(1) / transition.fps as u32;
+    let preview = ipc::Preview {
+        transition,
+        dimensions: args.size,
+        from: load(&args.from, args)?.into_boxed_slice(),
+        to: load(&args.to, args)?.into_boxed_slice(),
+    };
+
+    let frames = render_frames(&preview)?;
+    // every frame lasts as long as it would on screen, but we linger on the last one, so that it
+    // is possible to tell where the transition ends before the preview loops
+    let mut delays = vec![frame_time; frames.len()];
+    if let Some(last) = delays.last_mut() {
+        *last += args.hold;
+    }
+
+    let file = File::create(&args.output)
+        .map_err(|e| format!("failed to create {}: {e}", args.output.display()))?;
+    let writer = BufWriter::new(file);
+    match format {
+        PreviewFormat::Gif => gif(writer, args.size, &frames, &delays),
+        PreviewFormat::Apng => apng(writer, args.size, &frames, &delays),
+    }
+}
+
+fn format_of(path: &Path) -> Result<PreviewFormat, String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("gif") => Ok(PreviewFormat::Gif),
+        Some("png" | "apng") => Ok(PreviewFormat::Apng),
+        _ => Err(format!(
+            "can't tell which format to write {} in from its extension. Use --format",
+            path.display()
+        )),
+    }
+}
+
+/// Loads an image, and resizes it to the preview like `swww img` does to outputs
+fn load(path: &Path, args: &cli::Preview) -> Result<Vec<u8>, String> {
+    let img = ImgBuf::new(path)?.decode()?;
+    match args.resize {
+        ResizeStrategy::No => img_pad(img, args.size, &args.fill_color),
+        ResizeStrategy::Crop => img_resize_crop(img, args.size, make_filter(&args.filter)),
+        ResizeStrategy::Fit => {
+            img_resize_fit(img, args.size, make_filter(&args.filter), &args.fill_color)
+        }
+    }
+}
+
+/// Has `swww-daemon --preview` draw the transition, and returns its frames as RGB
+fn render_frames(preview: &ipc::Preview) -> Result<Vec<Vec<u8>>, String> {
+    let bytes = preview.to_bytes()?;
+    let mut daemon = Command::new("swww-daemon")
+        .arg("--preview")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("error spawning swww-daemon: {e}"))?;
+
+    // the daemon reads everything we send before it draws anything, so we can't deadlock
+    let sent = daemon
+        .stdin
+        .take()
+        .expect("swww-daemon's stdin is piped")
+        .write_all(&bytes);
+    let mut stdout = daemon.stdout.take().expect("swww-daemon's stdout is piped");
+    let (width, height) = preview.dimensions;
+    let mut frame = vec![0; width as usize * height as usize * 4];
+    let mut frames = Vec::new();
+    let received = loop {
+        match stdout.read_exact(&mut frame) {
+            Ok(()) => frames.push(
+                frame
+                    .chunks_exact(4)
+                    .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+                    .collect(),
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    // the daemon prints why it failed to stderr, which it shares with us
+    match daemon.wait() {
+        Ok(status) if status.success() => (),
+        Ok(_) => return Err("swww-daemon failed to render the preview".to_string()),
+        Err(e) => return Err(format!("failed to wait for swww-daemon: {e}")),
+    }
+    sent.map_err(|e| format!("failed to send the preview to swww-daemon: {e}"))?;
+    received.map_err(|e| format!("failed to read the preview from swww-daemon: {e}"))?;
+    if frames.is_empty() {
+        return Err("swww-daemon did not render any frames".to_string());
+    }
+    Ok(frames)
+}
+
+fn gif(
+    writer: impl Write,
+    (width, height): (u32, u32),
+    frames: &[Vec<u8>],
+    delays: &[Duration],
+) -> Result<(), String> {
+    let mut encoder = GifEncoder::new_with_speed(writer, 10);
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .map_err(|e| format!("failed to write the preview: {e}"))?;
+    for (frame, delay) in frames.iter().zip(delays) {
+        let rgba = frame
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect();
+        let rgba = RgbaImage::from_raw(width, height, rgba).expect("frames match the dimensions");
+        let delay = Delay::from_saturating_duration(*delay);
+        encoder
+            .encode_frame(Frame::from_parts(rgba, 0, 0, delay))
+            .map_err(|e| format!("failed to write the preview: {e}"))?;
+    }
+    Ok(())
+}
+
+fn apng(
+    writer: impl Write,
+    (width, height): (u32, u32),
+    frames: &[Vec<u8>],
+    delays: &[Duration],
+) -> Result<(), String> {
+    let to_string = |e: png::EncodingError| format!("failed to write the preview: {e}");
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, 0)
+        .map_err(to_string)?;
+    let mut writer = encoder.write_header().map_err(to_string)?;
+    for (frame, delay) in frames.iter().zip(delays) {
+        let millis = delay.as_millis().min(u16::MAX as u128) as u16;
+        writer.set_frame_delay(millis, 1000).map_err(to_string)?;
+        writer.write_image_data(frame).map_err(to_string)?;
+    }
+    writer.finish().map_err(to_string)
+}
@@ -1,90 +1,679 @@
 //! Implements basic cache functionality.
 //!
 //! The idea is:
-//!   1. the client registers the last image sent for each output in a file
-//!   2. the daemon spawns a client that reloads that image when an output is created
+//!   1. the client registers the last image sent for each output in a file, together with the
+//!      parameters it was processed with
+//!   2. the daemon reloads that image by itself when an output is created
 
 use std::{
+    ffi::CString,
+    fmt,
     fs::File,
     io::{BufReader, BufWriter, Read, Write},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use rkyv::{Deserialize, Infallible};
+use rkyv::{AlignedVec, Deserialize, Infallible};
 
-use crate::ipc::Animation;
+use crate::{
+    imgproc::{Filter, FrameOrder, ResizeStrategy, Span},
+    ipc::{Animation, Coord, Position, Transition, TransitionType},
+};
+
+/// Everything we need to display an image exactly like the user last did
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedImg {
+    pub path: String,
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+    pub transition: Option<Transition>,
+    /// Animation playback options, ignored for still images
+    pub order: FrameOrder,
+    pub speed: f32,
+    pub loops: u32,
+    /// The playlist running on the output, if any. `path` is its current image
+    pub playlist: Option<CachedPlaylist>,
+    /// The output's part of the image, when it was spanned across several outputs
+    pub span: Option<Span>,
+}
+
+/// A slideshow the daemon is running on an output, so that it can resume where it left off
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedPlaylist {
+    pub paths: Vec<String>,
+    /// Index of the image currently displayed
+    pub position: usize,
+    pub interval: Duration,
+}
+
+impl CachedImg {
+    /// Entry for an image that was set with all the default options and no transition
+    #[must_use]
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            resize: ResizeStrategy::default(),
+            filter: Filter::default(),
+            fill_color: [0, 0, 0],
+            transition: None,
+            order: FrameOrder::default(),
+            speed: 1.0,
+            loops: 0,
+            playlist: None,
+            span: None,
+        }
+    }
+}
+
+/// The format is the image path in the first line (which is all older versions wrote), followed
+/// by one `key=value` line per parameter. Missing parameters keep their default values.
+impl FromStr for CachedImg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines();
+        let path = lines.next().unwrap_or_default();
+        let mut cached = Self::new(path.to_string());
+
+        let mut transition = None;
+        let mut transition_type = None;
+        let mut playlist = CachedPlaylist {
+            paths: Vec::new(),
+            position: 0,
+            interval: Duration::ZERO,
+        };
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("malformed cache line: {line}"))?;
+            let transition = transition.get_or_insert_with(default_transition);
+            match key {
+                "resize" => cached.resize = value.parse()?,
+                "filter" => cached.filter = value.parse()?,
+                "fill-color" => cached.fill_color = parse_color(value)?,
+                "frame-order" => cached.order = value.parse()?,
+                "speed" => cached.speed = parse_num(value)?,
+                "loops" => cached.loops = parse_num(value)?,
+                "span" => cached.span = Some(value.parse()?),
+                "transition-type" => transition_type = Some(parse_transition_type(value)?),
+                "transition-step" => transition.step = parse_num(value)?,
+                "transition-duration" => transition.duration = parse_num(value)?,
+                "transition-fps" => transition.fps = parse_num(value)?,
+                "transition-angle" => transition.angle = parse_num(value)?,
+                "transition-pos" => {
+                    let (x, y) = value
+                        .split_once(',')
+                        .ok_or_else(|| format!("malformed transition position: {value}"))?;
+                    transition.pos = Position::new(parse_coord(x)?, parse_coord(y)?);
+                }
+                "transition-bezier" => {
+                    let v = parse_floats::<4>(value)?;
+                    transition.bezier = (v[0], v[1], v[2], v[3]);
+                }
+                "transition-wave" => {
+                    let v = parse_floats::<2>(value)?;
+                    transition.wave = (v[0], v[1]);
+                }
+                "invert-y" => transition.invert_y = parse_num(value)?,
+                "playlist-path" => playlist.paths.push(value.to_string()),
+                "playlist-position" => playlist.position = parse_num(value)?,
+                "playlist-interval" => {
+                    playlist.interval = Duration::try_from_secs_f64(parse_num(value)?)
+                        .map_err(|e| format!("invalid playlist interval in cache: {e}"))?
+                }
+                // ignore keys from newer versions, so that downgrading does not lose the cache
+                _ => (),
+            }
+        }
+
+        // the transition is only meaningful if we know its type
+        if let (Some(mut transition), Some(transition_type)) = (transition, transition_type) {
+            transition.transition_type = transition_type;
+            cached.transition = Some(transition);
+        }
+        if !playlist.paths.is_empty() {
+            playlist.position %= playlist.paths.len();
+            cached.playlist = Some(playlist);
+        }
+        Ok(cached)
+    }
+}
+
+impl fmt::Display for CachedImg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [r, g, b] = self.fill_color;
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "resize={}", self.resize)?;
+        writeln!(f, "filter={}", self.filter)?;
+        writeln!(f, "fill-color={r:02x}{g:02x}{b:02x}")?;
+        writeln!(f, "frame-order={}", self.order)?;
+        writeln!(f, "speed={}", self.speed)?;
+        writeln!(f, "loops={}", self.loops)?;
+        if let Some(span) = &self.span {
+            writeln!(f, "span={span}")?;
+        }
+        if let Some(t) = &self.transition {
+            let transition_type = match t.transition_type {
+                TransitionType::Simple => "simple",
+                TransitionType::Fade => "fade",
+                TransitionType::Outer => "outer",
+                TransitionType::Wipe => "wipe",
+                TransitionType::Grow => "grow",
+                TransitionType::Wave => "wave",
+            };
+            writeln!(f, "transition-type={transition_type}")?;
+            writeln!(f, "transition-step={}", t.step)?;
+            writeln!(f, "transition-duration={}", t.duration)?;
+            writeln!(f, "transition-fps={}", t.fps)?;
+            writeln!(f, "transition-angle={}", t.angle)?;
+            writeln!(
+                f,
+                "transition-pos={},{}",
+                fmt_coord(&t.pos.x),
+                fmt_coord(&t.pos.y)
+            )?;
+            let (b0, b1, b2, b3) = t.bezier;
+            writeln!(f, "transition-bezier={b0},{b1},{b2},{b3}")?;
+            writeln!(f, "transition-wave={},{}", t.wave.0, t.wave.1)?;
+            writeln!(f, "invert-y={}", t.invert_y)?;
+        }
+        if let Some(playlist) = &self.playlist {
+            writeln!(f, "playlist-interval={}", playlist.interval.as_secs_f64())?;
+            writeln!(f, "playlist-position={}", playlist.position)?;
+            for path in &playlist.paths {
+                writeln!(f, "playlist-path={path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_transition() -> Transition {
+    Transition {
+        transition_type: TransitionType::Simple,
+        duration: 3.0,
+        step: 90,
+        fps: 30,
+        angle: 45.0,
+        pos: Position::new(Coord::Percent(0.5), Coord::Percent(0.5)),
+        bezier: (0.54, 0.0, 0.34, 0.99),
+        wave: (20.0, 20.0),
+        invert_y: false,
+    }
+}
+
+fn parse_transition_type(s: &str) -> Result<TransitionType, String> {
+    match s {
+        "simple" => Ok(TransitionType::Simple),
+        "fade" => Ok(TransitionType::Fade),
+        "outer" => Ok(TransitionType::Outer),
+        "wipe" => Ok(TransitionType::Wipe),
+        "grow" => Ok(TransitionType::Grow),
+        "wave" => Ok(TransitionType::Wave),
+        _ => Err(format!("unknown transition type: {s}")),
+    }
+}
+
+fn parse_num<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("invalid value in cache: {s}"))
+}
+
+fn parse_floats<const N: usize>(s: &str) -> Result<[f32; N], String> {
+    let mut values = [0.0; N];
+    let mut split = s.split(',');
+    for value in values.iter_mut() {
+        *value = parse_num(split.next().unwrap_or_default())?;
+    }
+    if split.next().is_some() {
+        return Err(format!("too many values in cache: {s}"));
+    }
+    Ok(values)
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    if s.len() != 6 || !s.is_ascii() {
+        return Err(format!("invalid color in cache: {s}"));
+    }
+    let mut color = [0; 3];
+    for (i, c) in color.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid color in cache: {s}"))?;
+    }
+    Ok(color)
+}
+
+fn parse_coord(s: &str) -> Result<Coord, String> {
+    match s.strip_suffix("px") {
+        Some(pixel) => Ok(Coord::Pixel(parse_num(pixel)?)),
+        None => Ok(Coord::Percent(parse_num(s)?)),
+    }
+}
 
-pub fn store(output_name: &str, img_path: &str) -> Result<(), String> {
+fn fmt_coord(coord: &Coord) -> String {
+    match coord {
+        Coord::Pixel(x) => format!("{x}px"),
+        Coord::Percent(x) => format!("{x}"),
+    }
+}
+
+pub fn store(output_name: &str, img: &CachedImg) -> Result<(), String> {
     let mut filepath = cache_dir()?;
     filepath.push(output_name);
     let file = File::create(filepath).map_err(|e| e.to_string())?;
 
     let mut writer = BufWriter::new(file);
     writer
-        .write_all(img_path.as_bytes())
+        .write_all(img.to_string().as_bytes())
         .map_err(|e| format!("failed to write cache: {e}"))
 }
 
-pub fn store_animation_frames(animation: &Animation) -> Result<(), String> {
-    let filename = animation_filename(&PathBuf::from(&animation.path), animation.dimensions);
+/// Identifies a version of an animation's source file, so that we notice when it changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SourceStamp {
+    len: u64,
+    /// Time since the unix epoch
+    modified: Duration,
+}
+
+impl SourceStamp {
+    /// Every cached animation file starts with its source's stamp, in this many bytes
+    const SIZE: usize = 24;
+
+    fn of(path: &Path) -> Result<Self, String> {
+        let metadata = std::fs::metadata(path)
+            .map_err(|e| format!("failed to read metadata of {path:?}: {e}"))?;
+        let modified = metadata
+            .modified()
+            .map_err(|e| format!("failed to read modification time of {path:?}: {e}"))?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            len: metadata.len(),
+            modified,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.len.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.modified.as_secs().to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.modified.subsec_nanos().to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let len = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let secs = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let nanos = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        Self {
+            len,
+            modified: Duration::new(secs, nanos.min(999_999_999)),
+        }
+    }
+}
+
+/// Every cached animation file starts with this, followed by the archived [`Animation`]
+#[derive(Debug, PartialEq, Eq)]
+struct FramesHeader {
+    source: SourceStamp,
+    /// The image the frames were made from
+    path: String,
+}
+
+impl FramesHeader {
+    /// Paths longer than this are certainly from a corrupted file
+    const MAX_PATH_LEN: usize = 1 << 16;
+
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.source.to_bytes())?;
+        writer.write_all(&(self.path.len() as u64).to_le_bytes())?;
+        writer.write_all(self.path.as_bytes())
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self, String> {
+        let mut source = [0; SourceStamp::SIZE];
+        let mut len = [0; 8];
+        reader
+            .read_exact(&mut source)
+            .and_then(|()| reader.read_exact(&mut len))
+            .map_err(|e| format!("failed to read cache header: {e}"))?;
+        let len = u64::from_le_bytes(len) as usize;
+        if len > Self::MAX_PATH_LEN {
+            return Err(format!("cache header path is too long ({len} bytes)"));
+        }
+        let mut path = vec![0; len];
+        reader
+            .read_exact(&mut path)
+            .map_err(|e| format!("failed to read cache header: {e}"))?;
+        Ok(Self {
+            source: SourceStamp::from_bytes(&source),
+            path: String::from_utf8(path).map_err(|e| format!("invalid cache header: {e}"))?,
+        })
+    }
+}
+
+/// Stores the frames of `animation`, which were resized and ordered with the given parameters.
+/// Frames are only ever reused for the exact same parameters, so that changing any of them does not
+/// display stale frames.
+///
+/// Afterwards, evicts the least recently used frames until the cache fits in [`max_cache_size`].
+pub fn store_animation_frames(
+    animation: &Animation,
+    resize: ResizeStrategy,
+    filter: Filter,
+    fill_color: [u8; 3],
+    order: FrameOrder,
+) -> Result<(), String> {
+    let source = Path::new(&animation.path);
+    let filename = animation_filename(
+        source,
+        animation.dimensions,
+        resize,
+        filter,
+        fill_color,
+        order,
+    );
+    let cache_dir = cache_dir()?;
+    let filepath = cache_dir.join(&filename);
+    let header = FramesHeader {
+        source: SourceStamp::of(source)?,
+        path: animation.path.clone(),
+    };
+    write_frames(&filepath, &header, animation)?;
+    evict(&mut entries_in(&cache_dir)?, max_cache_size(), &filepath)?;
+    Ok(())
+}
+
+/// Loads the frames cached for the image in `path`, if they were made from its current contents.
+/// Frames from older versions of the image are deleted.
+pub fn load_animation_frames(
+    path: &Path,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: Filter,
+    fill_color: [u8; 3],
+    order: FrameOrder,
+) -> Result<Option<Animation>, String> {
+    let filename = animation_filename(path, dimensions, resize, filter, fill_color, order);
     let mut filepath = cache_dir()?;
-    filepath.push(&filename);
+    filepath.push(filename);
+    if !filepath.is_file() {
+        return Ok(None);
+    }
+    let frames = read_frames(&filepath, SourceStamp::of(path)?)?;
+    if frames.is_some() {
+        mark_used(&filepath);
+    }
+    Ok(frames)
+}
+
+fn write_frames(
+    filepath: &Path,
+    header: &FramesHeader,
+    animation: &Animation,
+) -> Result<(), String> {
+    // there is no point in rewriting frames that are already up to date
+    if File::open(filepath)
+        .is_ok_and(|mut f| FramesHeader::read(&mut f).is_ok_and(|h| h == *header))
+    {
+        return Ok(());
+    }
 
     let bytes = match rkyv::to_bytes::<_, 1024>(animation) {
         Ok(bytes) => bytes,
         Err(e) => return Err(format!("Failed to serialize request: {e}")),
     };
 
-    if !filepath.is_file() {
-        let file = File::create(filepath).map_err(|e| e.to_string())?;
+    let file = File::create(filepath).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+    header
+        .write(&mut writer)
+        .and_then(|()| writer.write_all(&bytes))
+        .map_err(|e| format!("failed to write cache: {e}"))
+}
 
-        let mut writer = BufWriter::new(file);
-        writer
-            .write_all(&bytes)
-            .map_err(|e| format!("failed to write cache: {e}"))
-    } else {
+fn read_frames(filepath: &Path, source: SourceStamp) -> Result<Option<Animation>, String> {
+    let file = File::open(filepath).map_err(|e| e.to_string())?;
+    let mut buf_reader = BufReader::new(file);
+    let is_current = FramesHeader::read(&mut buf_reader).is_ok_and(|h| h.source == source);
+    if !is_current {
+        // the image changed since we cached it (or the file is from before we stamped them)
+        std::fs::remove_file(filepath)
+            .map_err(|e| format!("failed to remove stale cache file `{filepath:?}`: {e}"))?;
+        return Ok(None);
+    }
+
+    let mut buf = AlignedVec::new();
+    buf.extend_from_reader(&mut buf_reader)
+        .map_err(|e| format!("failed to read file `{filepath:?}`: {e}"))?;
+
+    let frames = match rkyv::check_archived_root::<Animation>(&buf) {
+        Ok(frames) => frames,
+        Err(e) => {
+            // otherwise, we would never replace it, since its header is up to date
+            let _ = std::fs::remove_file(filepath);
+            return Err(format!("failed to validate cache file `{filepath:?}`: {e}"));
+        }
+    };
+    let frames: Animation = frames.deserialize(&mut Infallible).unwrap();
+
+    Ok(Some(frames))
+}
+
+/// We use the modification time of the cache files as the last time they were used
+fn mark_used(filepath: &Path) {
+    if let Err(e) = set_modified(filepath, None) {
+        eprintln!("WARNING: failed to mark cache file {filepath:?} as used: {e}");
+    }
+}
+
+/// Sets the modification time of `filepath` to `time`, or to now if it is `None`. We call
+/// `utimensat` ourselves, since `File::set_modified` needs a newer Rust than we support
+fn set_modified(filepath: &Path, time: Option<SystemTime>) -> std::io::Result<()> {
+    let path = CString::new(filepath.as_os_str().as_bytes())?;
+    let modified = match time {
+        Some(time) => {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            libc::timespec {
+                tv_sec: since_epoch.as_secs() as libc::time_t,
+                tv_nsec: since_epoch.subsec_nanos() as _,
+            }
+        }
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+    };
+    // leave the access time alone
+    let accessed = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_OMIT,
+    };
+    let times = [accessed, modified];
+    // SAFETY: `path` is a nul-terminated string and `times` holds the two timestamps `utimensat`
+    // reads, both of which outlive the call
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) } == 0 {
         Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
     }
 }
 
-pub fn load_animation_frames(
-    path: &Path,
-    dimensions: (u32, u32),
-) -> Result<Option<Animation>, String> {
-    let filename = animation_filename(path, dimensions);
-    let cache_dir = cache_dir()?;
-    let mut filepath = cache_dir.clone();
-    filepath.push(filename);
+/// Default for [`max_cache_size`]
+pub const DEFAULT_MAX_CACHE_SIZE: u64 = 512 << 20;
+
+/// How many bytes of animation frames we keep in the cache. Configurable through the
+/// `SWWW_CACHE_SIZE` environment variable, which accepts a number of bytes with an optional `K`,
+/// `M` or `G` suffix.
+#[must_use]
+pub fn max_cache_size() -> u64 {
+    match std::env::var("SWWW_CACHE_SIZE") {
+        Ok(size) => match parse_size(&size) {
+            Some(size) => size,
+            None => {
+                eprintln!(
+                    "WARNING: invalid SWWW_CACHE_SIZE ({size}), using the default of {DEFAULT_MAX_CACHE_SIZE} bytes"
+                );
+                DEFAULT_MAX_CACHE_SIZE
+            }
+        },
+        Err(_) => DEFAULT_MAX_CACHE_SIZE,
+    }
+}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 10),
+        'M' => (&s[..s.len() - 1], 20),
+        'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.trim().parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Cached frames of an animated image
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    /// The image the frames were made from, if we could read it
+    pub path: Option<String>,
+    /// Output dimensions and resize parameters the frames were made with, as encoded in their
+    /// file name
+    pub details: String,
+    /// Size on disk, in bytes
+    pub size: u64,
+    pub last_used: SystemTime,
+    file: PathBuf,
+}
+
+/// All cached animations, most recently used first
+pub fn entries() -> Result<Vec<CacheEntry>, String> {
+    entries_in(&cache_dir()?)
+}
 
+fn entries_in(cache_dir: &Path) -> Result<Vec<CacheEntry>, String> {
     let read_dir = cache_dir
         .read_dir()
         .map_err(|e| format!("failed to read cache directory ({cache_dir:?}): {e}"))?;
 
-    for entry in read_dir.into_iter().flatten() {
-        if entry.path() == filepath {
-            let file = File::open(&filepath).map_err(|e| e.to_string())?;
-            let mut buf_reader = BufReader::new(file);
-            let mut buf = Vec::new();
-            buf_reader
-                .read_to_end(&mut buf)
-                .map_err(|e| format!("failed to read file `{filepath:?}`: {e}"))?;
+    let mut entries = Vec::new();
+    for entry in read_dir.flatten() {
+        let filename = entry.file_name().to_string_lossy().to_string();
+        // the files named after the outputs hold the last image displayed on them
+        let details = match filename.rsplit_once("__") {
+            Some((_, details)) => match details.rfind("_v") {
+                Some(i) => details[..i].replace('_', ", "),
+                None => details.to_string(),
+            },
+            None => continue,
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("WARNING: failed to read metadata of {filename}: {e}");
+                continue;
+            }
+        };
+        let path = File::open(entry.path())
+            .ok()
+            .and_then(|mut f| FramesHeader::read(&mut f).ok())
+            .map(|header| header.path);
+        entries.push(CacheEntry {
+            path,
+            details,
+            size: metadata.len(),
+            last_used: metadata.modified().unwrap_or(UNIX_EPOCH),
+            file: entry.path(),
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.last_used));
+    Ok(entries)
+}
+
+/// Removes the least recently used `entries` until they fit in `max_size` bytes. We never remove
+/// `keep`, since it was just stored.
+///
+/// Returns how many entries were removed, and how many bytes that freed
+fn evict(
+    entries: &mut Vec<CacheEntry>,
+    max_size: u64,
+    keep: &Path,
+) -> Result<(usize, u64), String> {
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    let (mut count, mut freed) = (0, 0);
+    // entries are sorted from most to least recently used
+    while total > max_size {
+        let i = match entries.iter().rposition(|e| e.file != keep) {
+            Some(i) => i,
+            None => break,
+        };
+        let entry = entries.remove(i);
+        remove_entry(&entry)?;
+        total -= entry.size;
+        count += 1;
+        freed += entry.size;
+    }
+    Ok((count, freed))
+}
+
+fn remove_entry(entry: &CacheEntry) -> Result<(), String> {
+    std::fs::remove_file(&entry.file)
+        .map_err(|e| format!("failed to remove cache file {:?}: {e}", entry.file))
+}
 
-            let frames = unsafe { rkyv::archived_root::<Animation>(&buf) };
-            let frames: Animation = frames.deserialize(&mut Infallible).unwrap();
+/// Removes the frames of animations whose image no longer exists or changed, and then the least
+/// recently used ones until the cache fits in [`max_cache_size`].
+///
+/// Returns how many entries were removed, and how many bytes that freed
+pub fn prune() -> Result<(usize, u64), String> {
+    let mut entries = entries()?;
+    let (mut count, mut freed) = (0, 0);
+    let mut i = 0;
+    while i < entries.len() {
+        let stale = match File::open(&entries[i].file).map(|mut f| FramesHeader::read(&mut f)) {
+            Ok(Ok(header)) => SourceStamp::of(Path::new(&header.path)) != Ok(header.source),
+            _ => true,
+        };
+        if stale {
+            let entry = entries.remove(i);
+            remove_entry(&entry)?;
+            count += 1;
+            freed += entry.size;
+        } else {
+            i += 1;
+        }
+    }
 
-            return Ok(Some(frames));
+    let (evicted, evicted_bytes) = evict(&mut entries, max_cache_size(), Path::new(""))?;
+    Ok((count + evicted, freed + evicted_bytes))
+}
+
+/// Removes all cached frames of the image in `path`, returning how many entries were removed
+pub fn remove(path: &Path) -> Result<usize, String> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let path = path.to_string_lossy();
+    let mut count = 0;
+    for entry in entries()? {
+        if entry.path.as_deref() == Some(path.as_ref()) {
+            remove_entry(&entry)?;
+            count += 1;
         }
     }
-    Ok(None)
+    Ok(count)
 }
 
-pub fn get_previous_image_path(output_name: &str) -> Result<String, String> {
+pub fn get_previous_image(output_name: &str) -> Result<Option<CachedImg>, String> {
     let mut filepath = cache_dir()?;
     clean_previous_verions(&filepath);
 
     filepath.push(output_name);
     if !filepath.is_file() {
-        return Ok("".to_string());
+        return Ok(None);
     }
     let file = std::fs::File::open(filepath).map_err(|e| format!("failed to open file: {e}"))?;
     let mut reader = BufReader::new(file);
@@ -93,43 +682,37 @@ pub fn get_previous_image_path(output_name: &str) -> Result<String, String> {
         .read_to_end(&mut buf)
         .map_err(|e| format!("failed to read file: {e}"))?;
 
-    String::from_utf8(buf).map_err(|e| format!("failed to decode bytes: {e}"))
+    let s = String::from_utf8(buf).map_err(|e| format!("failed to decode bytes: {e}"))?;
+    if s.is_empty() {
+        return Ok(None);
+    }
+    s.parse().map(Some)
 }
 
-pub fn load(output_name: &str) -> Result<(), String> {
-    let img_path = get_previous_image_path(output_name)?;
-    if img_path.is_empty() {
-        return Ok(());
+/// Removes the cache of the current namespace. The default namespace leaves the caches of the
+/// others alone, even though they are inside its directory
+pub fn clean() -> Result<(), String> {
+    let cache_dir = cache_dir()?;
+    if crate::ipc::namespace().is_some() {
+        return std::fs::remove_dir_all(cache_dir)
+            .map_err(|e| format!("failed to remove cache directory: {e}"));
     }
-
-    if let Ok(mut child) = std::process::Command::new("pidof").arg("swww").spawn() {
-        if let Ok(status) = child.wait() {
-            if status.success() {
-                return Err("there is already another swww process running".to_string());
+    let read_dir = cache_dir
+        .read_dir()
+        .map_err(|e| format!("failed to read cache directory ({cache_dir:?}): {e}"))?;
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        let removed = if path.is_dir() {
+            if entry.file_name() == NAMESPACES_DIR {
+                continue;
             }
-        }
-    }
-
-    match std::process::Command::new("swww")
-        .arg("img")
-        .args([
-            &format!("--outputs={output_name}"),
-            "--transition-type=none",
-            &img_path,
-        ])
-        .spawn()
-    {
-        Ok(mut child) => match child.wait() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("child process failed: {e}")),
-        },
-        Err(e) => Err(format!("failed to spawn child process: {e}")),
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        removed.map_err(|e| format!("failed to remove {path:?} from the cache: {e}"))?;
     }
-}
-
-pub fn clean() -> Result<(), String> {
-    std::fs::remove_dir_all(cache_dir()?)
-        .map_err(|e| format!("failed to remove cache directory: {e}"))
+    Ok(())
 }
 
 fn clean_previous_verions(cache_dir: &Path) {
@@ -155,7 +738,7 @@ fn clean_previous_verions(cache_dir: &Path) {
 
         // only the images we've cached will have a _v token, indicating their version
         if let Some(i) = filename.rfind("_v") {
-            if &filename[i..] != current_version {
+            if &filename[i + 2..] != current_version {
                 if let Err(e) = std::fs::remove_file(entry.path()) {
                     eprintln!(
                         "WARNING: failed to remove cache file {} of old swww version {:?}",
@@ -169,34 +752,66 @@ fn clean_previous_verions(cache_dir: &Path) {
 
 fn create_dir(p: &Path) -> Result<(), String> {
     if !p.is_dir() {
-        if let Err(e) = std::fs::create_dir(p) {
+        if let Err(e) = std::fs::create_dir_all(p) {
             return Err(format!("failed to create directory({p:#?}): {e}"));
         }
     }
     Ok(())
 }
 
+/// The daemons in other namespaces keep their caches in subdirectories of this one, named after
+/// their namespace (see [`crate::ipc::set_namespace`])
+const NAMESPACES_DIR: &str = "namespaces";
+
 fn cache_dir() -> Result<PathBuf, String> {
-    if let Ok(path) = std::env::var("XDG_CACHE_HOME") {
-        let mut path: PathBuf = path.into();
-        path.push("swww");
-        create_dir(&path)?;
-        Ok(path)
+    let mut path: PathBuf = if let Ok(path) = std::env::var("XDG_CACHE_HOME") {
+        path.into()
     } else if let Ok(path) = std::env::var("HOME") {
         let mut path: PathBuf = path.into();
         path.push(".cache");
-        path.push("swww");
-        create_dir(&path)?;
-        Ok(path)
+        path
     } else {
-        Err("failed to read both $XDG_CACHE_HOME and $HOME environment variables".to_string())
+        return Err(
+            "failed to read both $XDG_CACHE_HOME and $HOME environment variables".to_string(),
+        );
+    };
+    path.push("swww");
+    if let Some(namespace) = crate::ipc::namespace() {
+        path.push(NAMESPACES_DIR);
+        path.push(namespace);
     }
+    create_dir(&path)?;
+    Ok(path)
 }
 
 #[must_use]
-fn animation_filename(path: &Path, dimensions: (u32, u32)) -> PathBuf {
+fn animation_filename(
+    path: &Path,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: Filter,
+    fill_color: [u8; 3],
+    order: FrameOrder,
+) -> PathBuf {
+    // only include the parameters that actually change the frames, so that we don't store the
+    // same ones twice
+    let filter = match resize {
+        ResizeStrategy::No => String::new(),
+        ResizeStrategy::Crop | ResizeStrategy::Fit => format!("_{filter}"),
+    };
+    let fill_color = match resize {
+        ResizeStrategy::Crop => String::new(),
+        ResizeStrategy::No | ResizeStrategy::Fit => {
+            let [r, g, b] = fill_color;
+            format!("_{r:02x}{g:02x}{b:02x}")
+        }
+    };
+    let order = match order {
+        FrameOrder::Forward => String::new(),
+        FrameOrder::Reverse | FrameOrder::PingPong => format!("_{order}"),
+    };
     format!(
-        "{}__{}x{}_v{}",
+        "{}__{}x{}_{resize}{filter}{fill_color}{order}_v{}",
         path.to_string_lossy().replace('/', "_"),
         dimensions.0,
         dimensions.1,
@@ -204,3 +819,208 @@ fn animation_filename(path: &Path, dimensions: (u32, u32)) -> PathBuf {
     )
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_img_roundtrip() {
+        let img = CachedImg {
+            path: "/home/user/wall paper.png".to_string(),
+            resize: ResizeStrategy::Fit,
+            filter: Filter::Nearest,
+            fill_color: [0x20, 0x20, 0x20],
+            transition: Some(Transition {
+                transition_type: TransitionType::Grow,
+                duration: 1.5,
+                step: 200,
+                fps: 144,
+                angle: 33.3,
+                pos: Position::new(Coord::Pixel(200.0), Coord::Percent(0.25)),
+                bezier: (0.1, 0.2, 0.3, 0.4),
+                wave: (10.0, 5.5),
+                invert_y: true,
+            }),
+            order: FrameOrder::PingPong,
+            speed: 0.5,
+            loops: 3,
+            playlist: Some(CachedPlaylist {
+                paths: vec![
+                    "/a.png".to_string(),
+                    "/home/user/wall paper.png".to_string(),
+                ],
+                position: 1,
+                interval: Duration::from_millis(90_500),
+            }),
+            span: Some(Span {
+                canvas: (5760, 1080),
+                position: (1920, 0),
+                size: (1920, 1080),
+            }),
+        };
+        assert_eq!(img.to_string().parse::<CachedImg>().unwrap(), img);
+
+        let img = CachedImg {
+            transition: None,
+            playlist: None,
+            span: None,
+            ..img
+        };
+        assert_eq!(img.to_string().parse::<CachedImg>().unwrap(), img);
+    }
+
+    #[test]
+    fn old_cache_files_use_the_defaults() {
+        let img: CachedImg = "/home/user/wallpaper.png".parse().unwrap();
+        assert_eq!(img, CachedImg::new("/home/user/wallpaper.png".to_string()));
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let img: CachedImg = "/a.png\nresize=no\nsome-future-key=1\n".parse().unwrap();
+        assert_eq!(img.resize, ResizeStrategy::No);
+    }
+
+    #[test]
+    fn animation_filenames_depend_on_resize_parameters() {
+        let path = Path::new("/a.gif");
+        let name = |resize, filter, color| {
+            animation_filename(path, (10, 10), resize, filter, color, FrameOrder::Forward)
+        };
+
+        let crop = name(ResizeStrategy::Crop, Filter::Lanczos3, [0, 0, 0]);
+        assert_ne!(crop, name(ResizeStrategy::Fit, Filter::Lanczos3, [0, 0, 0]));
+        assert_ne!(crop, name(ResizeStrategy::Crop, Filter::Nearest, [0, 0, 0]));
+        // the fill color does not affect cropped frames, and the filter does not affect padding
+        assert_eq!(
+            crop,
+            name(ResizeStrategy::Crop, Filter::Lanczos3, [1, 2, 3])
+        );
+        assert_eq!(
+            name(ResizeStrategy::No, Filter::Lanczos3, [1, 2, 3]),
+            name(ResizeStrategy::No, Filter::Nearest, [1, 2, 3])
+        );
+        assert_ne!(
+            name(ResizeStrategy::Fit, Filter::Lanczos3, [1, 2, 3]),
+            name(ResizeStrategy::Fit, Filter::Lanczos3, [0, 0, 0])
+        );
+
+        let ordered = |order| {
+            animation_filename(
+                path,
+                (10, 10),
+                ResizeStrategy::Crop,
+                Filter::Lanczos3,
+                [0; 3],
+                order,
+            )
+        };
+        assert_eq!(crop, ordered(FrameOrder::Forward));
+        assert_ne!(ordered(FrameOrder::Reverse), ordered(FrameOrder::PingPong));
+        assert_ne!(crop, ordered(FrameOrder::Reverse));
+    }
+
+    fn test_animation(path: &str) -> Animation {
+        Animation {
+            animation: Box::new([]),
+            path: path.to_string(),
+            dimensions: (10, 10),
+            wraps_around: true,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("swww-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn test_header(path: &str) -> FramesHeader {
+        FramesHeader {
+            source: SourceStamp {
+                len: 100,
+                modified: Duration::new(1_700_000_000, 5),
+            },
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn frames_are_invalidated_when_the_source_changes() {
+        let filepath = test_dir("frames").join("a.gif__10x10_crop_Lanczos3_v0");
+        let header = test_header("/a.gif");
+        write_frames(&filepath, &header, &test_animation("/a.gif")).unwrap();
+
+        let frames = read_frames(&filepath, header.source).unwrap().unwrap();
+        assert_eq!(frames.path, "/a.gif");
+
+        let edited = SourceStamp {
+            modified: Duration::new(1_700_000_001, 0),
+            ..header.source
+        };
+        assert!(read_frames(&filepath, edited).unwrap().is_none());
+        assert!(!filepath.exists(), "stale frames should be deleted");
+    }
+
+    #[test]
+    fn unstamped_frames_are_invalid() {
+        let filepath = test_dir("old-frames").join("a.gif__10x10_v0");
+        let bytes = rkyv::to_bytes::<_, 1024>(&test_animation("/a.gif")).unwrap();
+        std::fs::write(&filepath, &bytes).unwrap();
+        assert!(read_frames(&filepath, test_header("/a.gif").source)
+            .unwrap()
+            .is_none());
+        assert!(!filepath.exists());
+    }
+
+    #[test]
+    fn eviction_removes_least_recently_used_first() {
+        let dir = test_dir("evict");
+        for (i, name) in ["old", "mid", "new"].into_iter().enumerate() {
+            let filepath = dir.join(format!("{name}.gif__10x10_crop_Lanczos3_v0"));
+            let path = format!("/{name}.gif");
+            write_frames(&filepath, &test_header(&path), &test_animation(&path)).unwrap();
+            let modified = UNIX_EPOCH + Duration::from_secs(1000 * (i as u64 + 1));
+            set_modified(&filepath, Some(modified)).unwrap();
+            assert_eq!(filepath.metadata().unwrap().modified().unwrap(), modified);
+        }
+        mark_used(&dir.join("old.gif__10x10_crop_Lanczos3_v0"));
+        // the output entries must never be touched
+        std::fs::write(dir.join("DP-1"), "/old.gif").unwrap();
+
+        let mut entries = entries_in(&dir).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path.as_deref(), Some("/old.gif"));
+        assert_eq!(entries[0].details, "10x10, crop, Lanczos3");
+
+        let size = entries[0].size;
+        let keep = dir.join("mid.gif__10x10_crop_Lanczos3_v0");
+        let (count, freed) = evict(&mut entries, size, &keep).unwrap();
+        assert_eq!((count, freed), (2, 2 * size));
+        let left: Vec<_> = entries.iter().map(|e| e.path.clone().unwrap()).collect();
+        assert_eq!(left, ["/mid.gif"]);
+        assert!(dir.join("DP-1").exists());
+    }
+
+    #[test]
+    fn cache_sizes() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("4k"), Some(4 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size(" 2 G "), Some(2 << 30));
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!("/a.png\nresize=stretch".parse::<CachedImg>().is_err());
+        assert!("/a.png\nfill-color=zzzzzz".parse::<CachedImg>().is_err());
+        assert!("/a.png\ntransition-bezier=1,2,3"
+            .parse::<CachedImg>()
+            .is_err());
+        assert!("/a.png\nno-equals-sign".parse::<CachedImg>().is_err());
+    }
+}
@@ -15,6 +15,7 @@ macro_rules! decl_feature {
     ($feature:ident, $function:ident) => {
         static mut $feature: bool = false;
         #[inline(always)]
+        #[cfg_attr(test, allow(dead_code))] // tests call the specific implementations directly
         pub fn $function() -> bool {
             // SAFETY: we ensure this is false by default, and only changes ONCE, if someone calls
             // this module's init() function
@@ -44,6 +44,7 @@ extern "C" {
 
 /// This struct represents the cached difference between the previous frame and the next
 #[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 pub struct BitPack {
     inner: Box<[u8]>,
     /// This field will ensure we won't ever try to unpack the images on a buffer of the wrong size,
@@ -77,7 +78,7 @@ impl Compressor {
     /// # Panics:
     ///   * `prev.len() != cur.len()`
     ///   * the len of the diff buffer is larger than 0x7E000000. In practice, this can only
-    ///   happen for 64k monitors and beyond
+    ///     happen for 64k monitors and beyond
     #[inline]
     pub fn compress(&mut self, prev: &[u8], cur: &[u8]) -> Option<BitPack> {
         assert_eq!(
@@ -236,17 +237,26 @@ impl Decompressor {
             .compressed_size
             .deserialize(&mut rkyv::Infallible)
             .unwrap();
+        // archived bitpacks come from outside the process, so we can't assume they are sane
+        if cap <= 0 || cap as usize > LZ4_MAX_INPUT_SIZE {
+            return Err(format!("invalid compressed frame size: {cap}"));
+        }
         self.ensure_capacity(cap as usize);
 
-        // SAFETY: errors will never happen because BitPacked is *always* only produced
-        // with correct lz4 compression
-        unsafe {
+        // SAFETY: LZ4_decompress_safe never writes more than `cap` bytes, which is exactly the
+        // capacity we've ensured above
+        let n = unsafe {
             LZ4_decompress_safe(
                 archived.inner.as_ptr() as _,
                 self.ptr.as_ptr() as _,
                 archived.inner.len() as c_int,
                 cap as c_int,
-            );
+            )
+        };
+        if n != cap {
+            return Err(format!(
+                "corrupted frame: decompressed {n} bytes, expected {cap}"
+            ));
         }
 
         // SAFETY: the call to self.ensure_capacity guarantees the pointer has the necessary size
@@ -0,0 +1,551 @@
+//! Image processing shared by the client and the daemon
+//!
+//! The daemon uses this to display cached images by itself when an output is connected, without
+//! needing a client to do it
+
+use fast_image_resize::{FilterType, PixelType, Resizer};
+use image::RgbImage;
+use rkyv::{Archive, Deserialize, Serialize};
+use std::{fmt, num::NonZeroU32, path::Path, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub enum ResizeStrategy {
+    No,
+    #[default]
+    Crop,
+    Fit,
+}
+
+impl FromStr for ResizeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no" => Ok(Self::No),
+            "crop" => Ok(Self::Crop),
+            "fit" => Ok(Self::Fit),
+            _ => Err(format!("unknown resize strategy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for ResizeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::No => "no",
+            Self::Crop => "crop",
+            Self::Fit => "fit",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Mitchell,
+    #[default]
+    Lanczos3,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Nearest" => Ok(Self::Nearest),
+            "Bilinear" => Ok(Self::Bilinear),
+            "CatmullRom" => Ok(Self::CatmullRom),
+            "Mitchell" => Ok(Self::Mitchell),
+            "Lanczos3" => Ok(Self::Lanczos3),
+            _ => Err(format!("unknown filter: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Nearest => "Nearest",
+            Self::Bilinear => "Bilinear",
+            Self::CatmullRom => "CatmullRom",
+            Self::Mitchell => "Mitchell",
+            Self::Lanczos3 => "Lanczos3",
+        };
+        f.write_str(s)
+    }
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => FilterType::Box,
+            Filter::Bilinear => FilterType::Bilinear,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Mitchell => FilterType::Mitchell,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The order in which an animation's frames are played. Since frames are stored as differences
+/// from the previous one, this is decided before compressing them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameOrder {
+    #[default]
+    Forward,
+    /// Backwards, starting from the first frame
+    Reverse,
+    /// Forward and then backwards
+    PingPong,
+}
+
+impl FromStr for FrameOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(Self::Forward),
+            "reverse" => Ok(Self::Reverse),
+            "ping-pong" => Ok(Self::PingPong),
+            _ => Err(format!("unknown frame order: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for FrameOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Forward => "forward",
+            Self::Reverse => "reverse",
+            Self::PingPong => "ping-pong",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FrameOrder {
+    /// Reorders `frames` so that playing them in a loop follows this order. The first frame stays
+    /// the same, since it is the one displayed before the animation starts
+    #[must_use]
+    pub fn apply<T: Clone>(self, mut frames: Vec<T>) -> Vec<T> {
+        match self {
+            Self::Forward => (),
+            Self::Reverse => {
+                if frames.len() > 1 {
+                    frames[1..].reverse();
+                }
+            }
+            Self::PingPong => {
+                // the first and last frames are not repeated, or they would be shown twice in a row
+                let len = frames.len();
+                if len > 2 {
+                    frames.extend_from_within(1..len - 1);
+                    frames[len..].reverse();
+                }
+            }
+        }
+        frames
+    }
+}
+
+/// The part of an image spanned across several outputs that belongs to one of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// Size of the whole image, in pixels
+    pub canvas: (u32, u32),
+    /// Where the output's part starts
+    pub position: (u32, u32),
+    /// Size of the output's part. It is scaled to the output's size when they differ
+    pub size: (u32, u32),
+}
+
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid span: {s}"))?;
+        match values[..] {
+            [canvas_w, canvas_h, x, y, w, h] => Ok(Self {
+                canvas: (canvas_w, canvas_h),
+                position: (x, y),
+                size: (w, h),
+            }),
+            _ => Err(format!("invalid span: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{}",
+            self.canvas.0,
+            self.canvas.1,
+            self.position.0,
+            self.position.1,
+            self.size.0,
+            self.size.1
+        )
+    }
+}
+
+impl Span {
+    /// Lays out outputs, given as their logical position, logical size and scale factor, on a
+    /// single canvas, returning their spans in the same order.
+    ///
+    /// The canvas has the resolution of the output with the largest scale factor, and covers the
+    /// gaps between outputs, so that the image lines up across them
+    #[must_use]
+    #[allow(clippy::type_complexity)]
+    pub fn layout(outputs: &[((i32, i32), (u32, u32), i32)]) -> Vec<Self> {
+        let scale = outputs
+            .iter()
+            .map(|(_, _, scale)| *scale)
+            .max()
+            .unwrap_or(1)
+            .max(1) as u32;
+        let left = outputs.iter().map(|((x, _), _, _)| *x).min().unwrap_or(0);
+        let top = outputs.iter().map(|((_, y), _, _)| *y).min().unwrap_or(0);
+        let right = outputs
+            .iter()
+            .map(|((x, _), (w, _), _)| *x + *w as i32)
+            .max()
+            .unwrap_or(0);
+        let bottom = outputs
+            .iter()
+            .map(|((_, y), (_, h), _)| *y + *h as i32)
+            .max()
+            .unwrap_or(0);
+        let canvas = ((right - left) as u32 * scale, (bottom - top) as u32 * scale);
+        outputs
+            .iter()
+            .map(|((x, y), (w, h), _)| Self {
+                canvas,
+                position: ((x - left) as u32 * scale, (y - top) as u32 * scale),
+                size: (w * scale, h * scale),
+            })
+            .collect()
+    }
+}
+
+/// Cuts `span`'s part out of `canvas`, an image of size `span.canvas` in the format [`resize`]
+/// returns, and scales it to `dimensions`
+pub fn crop_span(
+    canvas: &[u8],
+    span: &Span,
+    dimensions: (u32, u32),
+    filter: Filter,
+) -> Result<Vec<u8>, String> {
+    let (canvas_w, canvas_h) = span.canvas;
+    let (x, y) = span.position;
+    let (w, h) = span.size;
+    if canvas.len() != canvas_w as usize * canvas_h as usize * 3
+        || x + w > canvas_w
+        || y + h > canvas_h
+        || w == 0
+        || h == 0
+    {
+        return Err(format!("span {span} does not fit in its canvas"));
+    }
+
+    let mut part = Vec::with_capacity(w as usize * h as usize * 3);
+    for row in y..y + h {
+        let start = (row as usize * canvas_w as usize + x as usize) * 3;
+        part.extend_from_slice(&canvas[start..start + w as usize * 3]);
+    }
+    if (w, h) == dimensions {
+        return Ok(part);
+    }
+
+    // the channels are resized independently, so their order does not matter here
+    let src = fast_image_resize::Image::from_vec_u8(
+        NonZeroU32::new(w).unwrap(),
+        NonZeroU32::new(h).unwrap(),
+        part,
+        PixelType::U8x3,
+    )
+    .map_err(|e| e.to_string())?;
+    let new_w = NonZeroU32::new(dimensions.0).ok_or("output width is zero")?;
+    let new_h = NonZeroU32::new(dimensions.1).ok_or("output height is zero")?;
+    let mut dst = fast_image_resize::Image::new(new_w, new_h, PixelType::U8x3);
+    let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter.into()));
+    resizer
+        .resize(&src.view(), &mut dst.view_mut())
+        .map_err(|e| e.to_string())?;
+    Ok(dst.into_vec())
+}
+
+/// Resizes `img` to `span`'s canvas and returns its part of it, scaled to `dimensions`
+pub fn resize_span(
+    img: RgbImage,
+    span: &Span,
+    dimensions: (u32, u32),
+    resize_strategy: ResizeStrategy,
+    filter: Filter,
+    fill_color: &[u8; 3],
+) -> Result<Vec<u8>, String> {
+    let canvas = resize(img, span.canvas, resize_strategy, filter, fill_color)?;
+    crop_span(&canvas, span, dimensions, filter)
+}
+
+/// Resizes `img` to `dimensions` with the given strategy, returning the bytes in BGR order
+pub fn resize(
+    img: RgbImage,
+    dimensions: (u32, u32),
+    resize: ResizeStrategy,
+    filter: Filter,
+    fill_color: &[u8; 3],
+) -> Result<Vec<u8>, String> {
+    match resize {
+        ResizeStrategy::No => img_pad(img, dimensions, fill_color),
+        ResizeStrategy::Crop => img_resize_crop(img, dimensions, filter.into()),
+        ResizeStrategy::Fit => img_resize_fit(img, dimensions, filter.into(), fill_color),
+    }
+}
+
+/// Decodes the image at `path`. For animated images, this is their first frame
+pub fn decode(path: &Path) -> Result<RgbImage, String> {
+    image::io::Reader::open(path)
+        .map_err(|e| format!("failed to open image: {e}"))?
+        .with_guessed_format()
+        .map_err(|e| format!("failed to detect the image's format: {e}"))?
+        .decode()
+        .map_err(|e| format!("failed to decode image: {e}"))
+        .map(|img| img.into_rgb8())
+}
+
+pub fn img_pad(
+    mut img: RgbImage,
+    dimensions: (u32, u32),
+    color: &[u8; 3],
+) -> Result<Vec<u8>, String> {
+    let (padded_w, padded_h) = dimensions;
+    let (padded_w, padded_h) = (padded_w as usize, padded_h as usize);
+    let mut padded = Vec::with_capacity(padded_h * padded_w * 3);
+
+    let img = {
+        if img.width() > dimensions.0 || img.height() > dimensions.1 {
+            let left = (img.width() - dimensions.0) / 2;
+            let top = (img.height() - dimensions.1) / 2;
+            image::imageops::crop(&mut img, left, top, dimensions.0, dimensions.1).to_image()
+        } else {
+            image::imageops::crop(&mut img, 0, 0, dimensions.0, dimensions.1).to_image()
+        }
+    };
+    let (img_w, img_h) = img.dimensions();
+    let (img_w, img_h) = (img_w as usize, img_h as usize);
+    let raw_img = img.into_vec();
+
+    for _ in 0..(((padded_h - img_h) / 2) * padded_w) {
+        padded.push(color[2]);
+        padded.push(color[1]);
+        padded.push(color[0]);
+    }
+
+    // Calculate left and right border widths. `u32::div` rounds toward 0, so, if `img_w` is odd,
+    // add an extra pixel to the right border to ensure the row is the correct width.
+    let left_border_w = (padded_w - img_w) / 2;
+    let right_border_w = left_border_w + (img_w % 2);
+
+    for row in 0..img_h {
+        for _ in 0..left_border_w {
+            padded.push(color[2]);
+            padded.push(color[1]);
+            padded.push(color[0]);
+        }
+
+        for pixel in raw_img[(row * img_w * 3)..((row + 1) * img_w * 3)].chunks_exact(3) {
+            padded.push(pixel[2]);
+            padded.push(pixel[1]);
+            padded.push(pixel[0]);
+        }
+        for _ in 0..right_border_w {
+            padded.push(color[2]);
+            padded.push(color[1]);
+            padded.push(color[0]);
+        }
+    }
+
+    while padded.len() < (padded_h * padded_w * 3) {
+        padded.push(color[2]);
+        padded.push(color[1]);
+        padded.push(color[0]);
+    }
+
+    Ok(padded)
+}
+
+/// Convert an RGB &[u8] to BRG in-place by swapping bytes
+#[inline]
+fn rgb_to_brg(rgb: &mut [u8]) {
+    for pixel in rgb.chunks_exact_mut(3) {
+        pixel.swap(0, 2);
+    }
+}
+
+/// Resize an image to fit within the given dimensions, covering as much space as possible without
+/// cropping.
+pub fn img_resize_fit(
+    img: RgbImage,
+    dimensions: (u32, u32),
+    filter: FilterType,
+    padding_color: &[u8; 3],
+) -> Result<Vec<u8>, String> {
+    let (width, height) = dimensions;
+    let (img_w, img_h) = img.dimensions();
+    if (img_w, img_h) != (width, height) {
+        // if our image is already scaled to fit, skip resizing it and just pad it directly
+        if img_w == width || img_h == height {
+            return img_pad(img, dimensions, padding_color);
+        }
+
+        let ratio = width as f32 / height as f32;
+        let img_r = img_w as f32 / img_h as f32;
+
+        let (trg_w, trg_h) = if ratio > img_r {
+            let scale = height as f32 / img_h as f32;
+            ((img_w as f32 * scale) as u32, height)
+        } else {
+            let scale = width as f32 / img_w as f32;
+            (width, (img_h as f32 * scale) as u32)
+        };
+
+        let src = match fast_image_resize::Image::from_vec_u8(
+            // We unwrap below because we know the images's dimensions should never be 0
+            NonZeroU32::new(img_w).unwrap(),
+            NonZeroU32::new(img_h).unwrap(),
+            img.into_raw(),
+            PixelType::U8x3,
+        ) {
+            Ok(i) => i,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // We unwrap below because we know the outputs's dimensions should never be 0
+        let new_w = NonZeroU32::new(trg_w).unwrap();
+        let new_h = NonZeroU32::new(trg_h).unwrap();
+
+        let mut dst = fast_image_resize::Image::new(new_w, new_h, PixelType::U8x3);
+        let mut dst_view = dst.view_mut();
+
+        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
+        if let Err(e) = resizer.resize(&src.view(), &mut dst_view) {
+            return Err(e.to_string());
+        }
+
+        img_pad(
+            image::RgbImage::from_raw(trg_w, trg_h, dst.into_vec()).unwrap(),
+            dimensions,
+            padding_color,
+        )
+    } else {
+        let mut res = img.into_vec();
+        // The ARGB is 'little endian', so here we must  put the order
+        // of bytes 'in reverse', so it needs to be BGRA.
+        rgb_to_brg(&mut res);
+        Ok(res)
+    }
+}
+
+pub fn img_resize_crop(
+    img: RgbImage,
+    dimensions: (u32, u32),
+    filter: FilterType,
+) -> Result<Vec<u8>, String> {
+    let (width, height) = dimensions;
+    let (img_w, img_h) = img.dimensions();
+    let mut resized_img = if (img_w, img_h) != (width, height) {
+        let src = match fast_image_resize::Image::from_vec_u8(
+            // We unwrap below because we know the images's dimensions should never be 0
+            NonZeroU32::new(img_w).unwrap(),
+            NonZeroU32::new(img_h).unwrap(),
+            img.into_raw(),
+            PixelType::U8x3,
+        ) {
+            Ok(i) => i,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // We unwrap below because we know the outputs's dimensions should never be 0
+        let new_w = NonZeroU32::new(width).unwrap();
+        let new_h = NonZeroU32::new(height).unwrap();
+        let mut src_view = src.view();
+        src_view.set_crop_box_to_fit_dst_size(new_w, new_h, Some((0.5, 0.5)));
+
+        let mut dst = fast_image_resize::Image::new(new_w, new_h, PixelType::U8x3);
+        let mut dst_view = dst.view_mut();
+
+        let mut resizer = Resizer::new(fast_image_resize::ResizeAlg::Convolution(filter));
+        if let Err(e) = resizer.resize(&src_view, &mut dst_view) {
+            return Err(e.to_string());
+        }
+
+        dst.into_vec()
+    } else {
+        img.into_vec()
+    };
+
+    // The ARGB is 'little endian', so here we must  put the order
+    // of bytes 'in reverse', so it needs to be BGRA.
+    rgb_to_brg(&mut resized_img);
+
+    Ok(resized_img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_orders() {
+        let frames = vec![0, 1, 2, 3];
+        assert_eq!(FrameOrder::Forward.apply(frames.clone()), [0, 1, 2, 3]);
+        assert_eq!(FrameOrder::Reverse.apply(frames.clone()), [0, 3, 2, 1]);
+        assert_eq!(FrameOrder::PingPong.apply(frames), [0, 1, 2, 3, 2, 1]);
+        assert_eq!(FrameOrder::PingPong.apply(vec![0, 1]), [0, 1]);
+        assert_eq!(FrameOrder::Reverse.apply(Vec::<u8>::new()), []);
+    }
+
+    #[test]
+    fn span_layout() {
+        // a scale 2 laptop below and to the left of a scale 1 monitor, with a gap between them
+        let spans = Span::layout(&[((0, 1080), (1280, 800), 2), ((1300, 0), (1920, 1080), 1)]);
+        assert_eq!(spans[0].canvas, (3220 * 2, 1880 * 2));
+        assert_eq!(spans[0].position, (0, 2160));
+        assert_eq!(spans[0].size, (2560, 1600));
+        assert_eq!(spans[1].position, (2600, 0));
+        assert_eq!(spans[1].size, (3840, 2160));
+        assert_eq!(spans[1].to_string().parse(), Ok(spans[1]));
+    }
+
+    #[test]
+    fn cropping_spans() {
+        // 4x2 canvas, where each pixel's bytes are its index
+        let canvas: Vec<u8> = (0..8).flat_map(|i| [i; 3]).collect();
+        let span = Span {
+            canvas: (4, 2),
+            position: (1, 0),
+            size: (2, 2),
+        };
+        let part = crop_span(&canvas, &span, (2, 2), Filter::Nearest).unwrap();
+        assert_eq!(part, [1, 1, 1, 2, 2, 2, 5, 5, 5, 6, 6, 6]);
+        let part = crop_span(&canvas, &span, (1, 1), Filter::Nearest).unwrap();
+        assert_eq!(part.len(), 3);
+
+        let span = Span {
+            position: (3, 0),
+            ..span
+        };
+        assert!(crop_span(&canvas, &span, (2, 2), Filter::Nearest).is_err());
+    }
+}
@@ -1,22 +1,28 @@
-use rkyv::{Archive, Deserialize, Serialize};
+use rkyv::{AlignedVec, Archive, Deserialize, Serialize};
 use std::{
     fmt,
     io::{BufReader, BufWriter, Read, Write},
     os::unix::net::UnixStream,
     path::{Path, PathBuf},
+    sync::OnceLock,
     time::Duration,
 };
 
-use crate::{cache, compression::BitPack};
+use crate::{
+    compression::BitPack,
+    imgproc::{Filter, ResizeStrategy},
+};
 
-#[derive(PartialEq, Archive, Serialize)]
+#[derive(Clone, Debug, PartialEq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 #[archive_attr(derive(Clone))]
 pub enum Coord {
     Pixel(f32),
     Percent(f32),
 }
 
-#[derive(PartialEq, Archive, Serialize)]
+#[derive(Clone, Debug, PartialEq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 #[archive_attr(derive(Clone))]
 pub struct Position {
     pub x: Coord,
@@ -102,6 +108,7 @@ impl ArchivedPosition {
 }
 
 #[derive(Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 #[archive_attr(derive(PartialEq))]
 pub enum BgImg {
     Color([u8; 3]),
@@ -127,6 +134,22 @@ impl ArchivedBgImg {
     }
 }
 
+impl ArchivedBgImg {
+    /// Serializes the bg img as a JSON object, as documented in `swww-query(1)`
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        match self {
+            ArchivedBgImg::Color(c) => format!(
+                r#"{{"type":"color","color":"{:02x}{:02x}{:02x}"}}"#,
+                c[0], c[1], c[2]
+            ),
+            ArchivedBgImg::Img(path) => {
+                format!(r#"{{"type":"image","path":{}}}"#, json_string(path))
+            }
+        }
+    }
+}
+
 impl fmt::Display for ArchivedBgImg {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -138,35 +161,158 @@ impl fmt::Display for ArchivedBgImg {
     }
 }
 
+/// How an output is rotated (counter-clockwise) and flipped, like `wl_output::Transform`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rotated90,
+    Rotated180,
+    Rotated270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl ArchivedTransform {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Rotated90 => "90",
+            Self::Rotated180 => "180",
+            Self::Rotated270 => "270",
+            Self::Flipped => "flipped",
+            Self::Flipped90 => "flipped-90",
+            Self::Flipped180 => "flipped-180",
+            Self::Flipped270 => "flipped-270",
+        }
+    }
+}
+
 #[derive(Clone, Archive, Serialize)]
+#[archive(check_bytes)]
 pub struct BgInfo {
     pub name: String,
     pub dim: (u32, u32),
+    /// Logical position of the output in the compositor's space
+    pub position: (i32, i32),
     pub scale_factor: i32,
+    /// The scale we actually draw at, in 120ths, when the compositor supports fractional scaling
+    pub fractional_scale: Option<u32>,
+    /// We always draw upright images, since `dim` already accounts for the transform, and let the
+    /// compositor rotate them
+    pub transform: Transform,
     pub img: BgImg,
+    /// Whether an animated image is currently playing
+    pub animating: bool,
+    /// Whether we are in the middle of transitioning to a new image
+    pub transitioning: bool,
+}
+
+/// The size, in pixels, of the buffers for an output of logical size `dim`
+fn real_dim(dim: (u32, u32), scale_factor: i32, fractional_scale: Option<u32>) -> (u32, u32) {
+    match fractional_scale {
+        // rounding halfway away from zero, like wp_fractional_scale_v1 asks
+        Some(scale) => ((dim.0 * scale + 60) / 120, (dim.1 * scale + 60) / 120),
+        None => (dim.0 * scale_factor as u32, dim.1 * scale_factor as u32),
+    }
 }
 
 impl BgInfo {
     #[must_use]
     pub fn real_dim(&self) -> (u32, u32) {
-        (
-            self.dim.0 * self.scale_factor as u32,
-            self.dim.1 * self.scale_factor as u32,
+        real_dim(self.dim, self.scale_factor, self.fractional_scale)
+    }
+}
+
+impl ArchivedBgInfo {
+    #[must_use]
+    pub fn real_dim(&self) -> (u32, u32) {
+        let fractional_scale = self.fractional_scale.as_ref().copied();
+        real_dim(
+            (self.dim.0, self.dim.1),
+            self.scale_factor,
+            fractional_scale,
+        )
+    }
+
+    /// The scale we draw at, which may be fractional
+    #[must_use]
+    pub fn scale(&self) -> f64 {
+        match self.fractional_scale.as_ref() {
+            Some(scale) => *scale as f64 / 120.0,
+            None => self.scale_factor as f64,
+        }
+    }
+
+    /// Serializes the info as a single line JSON object.
+    ///
+    /// The schema is documented in `swww-query(1)`. Fields may be added, but existing ones must
+    /// keep their names and meaning, since people parse this in scripts
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let (real_width, real_height) = self.real_dim();
+        format!(
+            r#"{{"name":{},"width":{},"height":{},"x":{},"y":{},"scale_factor":{},"scale":{},"transform":"{}","real_width":{},"real_height":{},"displaying":{},"animating":{},"transitioning":{}}}"#,
+            json_string(&self.name),
+            self.dim.0,
+            self.dim.1,
+            self.position.0,
+            self.position.1,
+            self.scale_factor,
+            self.scale(),
+            self.transform.as_str(),
+            real_width,
+            real_height,
+            self.img.to_json(),
+            self.animating,
+            self.transitioning,
         )
     }
 }
 
+/// Quotes and escapes `s` as a JSON string
+#[must_use]
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
 impl fmt::Display for ArchivedBgInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}: {}x{}, scale: {}, currently displaying: {}",
-            self.name, self.dim.0, self.dim.1, self.scale_factor, self.img
-        )
+            "{}: {}x{}, scale: {}, ",
+            self.name,
+            self.dim.0,
+            self.dim.1,
+            self.scale()
+        )?;
+        if !matches!(self.transform, ArchivedTransform::Normal) {
+            write!(f, "transform: {}, ", self.transform.as_str())?;
+        }
+        write!(f, "currently displaying: {}", self.img)
     }
 }
 
-#[derive(Archive, Serialize)]
+#[derive(Clone, Debug, PartialEq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 #[archive_attr(derive(Clone))]
 pub enum TransitionType {
     Simple,
@@ -177,7 +323,8 @@ pub enum TransitionType {
     Wave,
 }
 
-#[derive(Archive, Serialize)]
+#[derive(Clone, Debug, PartialEq, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 #[archive_attr(derive(Clone))]
 pub struct Transition {
     pub transition_type: TransitionType,
@@ -192,28 +339,123 @@ pub struct Transition {
 }
 
 #[derive(Archive, Serialize)]
+#[archive(check_bytes)]
 pub struct Clear {
     pub color: [u8; 3],
     pub outputs: Box<[String]>,
 }
 
+/// What `swww preview` sends through the stdin of `swww-daemon --preview`. It never goes through
+/// the socket, so it is not part of the protocol
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct Preview {
+    pub transition: Transition,
+    pub dimensions: (u32, u32),
+    /// Both images are in the same format as [`Img::img`], already resized to `dimensions`
+    pub from: Box<[u8]>,
+    pub to: Box<[u8]>,
+}
+
+impl Preview {
+    /// Serializes the preview. Since it goes through a pipe that is closed right after, it has no
+    /// length prefix
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        match rkyv::to_bytes::<_, 1024>(self) {
+            Ok(bytes) => Ok(bytes.into_vec()),
+            Err(e) => Err(format!("Failed to serialize preview: {e}")),
+        }
+    }
+
+    pub fn receive(bytes: &[u8]) -> Result<&ArchivedPreview, String> {
+        rkyv::check_archived_root::<Self>(bytes)
+            .map_err(|e| format!("invalid preview (is swww-daemon the same version as swww?): {e}"))
+    }
+}
+
 #[derive(Archive, Serialize)]
+#[archive(check_bytes)]
 pub struct Img {
     pub path: String,
     pub img: Box<[u8]>,
 }
 
 #[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
 pub struct Animation {
     pub animation: Box<[(BitPack, Duration)]>,
     pub path: String,
     pub dimensions: (u32, u32),
+    /// Whether the last frame goes back to the first one. It doesn't when they are identical
+    pub wraps_around: bool,
 }
 
-pub type AnimationRequest = Box<[(Animation, Box<[String]>)]>;
+/// How the daemon plays animations back. Everything that changes the frames themselves (like
+/// playing them in reverse) is done by the client instead, when compressing them
+#[derive(Clone, Copy, Debug, PartialEq, Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct AnimationPlayback {
+    /// Multiplies the speed of every frame
+    pub speed: f32,
+    /// How many times to play the animation before stopping on its last frame. 0 means forever
+    pub loops: u32,
+}
+
+impl AnimationPlayback {
+    /// The speeds we accept. Much slower or faster animations would make their frame durations
+    /// overflow or round down to nothing
+    pub const SPEEDS: std::ops::RangeInclusive<f32> = 0.01..=100.0;
+}
+
+impl Default for AnimationPlayback {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            loops: 0,
+        }
+    }
+}
+
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct Step {
+    /// How many frames to advance
+    pub frames: u32,
+    pub outputs: Box<[String]>,
+}
+
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub struct Visibility {
+    pub visible: bool,
+    pub outputs: Box<[String]>,
+}
+
+/// A slideshow for the daemon to run by itself
+#[derive(Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub struct Playlist {
+    /// The images, in the order they should be displayed
+    pub paths: Box<[String]>,
+    /// Index of the first image to display
+    pub position: u32,
+    /// How long each image is displayed
+    pub interval: Duration,
+    pub resize: ResizeStrategy,
+    pub filter: Filter,
+    pub fill_color: [u8; 3],
+    /// Used every time the image changes
+    pub transition: Transition,
+    /// Display the first image without a transition, eg: when restoring the playlist
+    pub immediate: bool,
+    pub outputs: Box<[String]>,
+}
+
+pub type AnimationRequest = (AnimationPlayback, Box<[(Animation, Box<[String]>)]>);
 pub type ImageRequest = (Transition, Box<[(Img, Box<[String]>)]>);
 
 #[derive(Archive, Serialize)]
+#[archive(check_bytes)]
 pub enum Request {
     Animation(AnimationRequest),
     Clear(Clear),
@@ -221,6 +463,29 @@ pub enum Request {
     Kill,
     Query,
     Img(ImageRequest),
+    /// Keep the connection open and stream [`Event`]s through it
+    Subscribe,
+    /// Freeze the animations in these outputs (or all of them, if empty) on their current frame
+    Pause(Box<[String]>),
+    /// Resume paused animations
+    Resume(Box<[String]>),
+    /// Pause animations and advance them by some frames
+    Step(Step),
+    /// Tell the daemon whether outputs are visible (eg: because they are idle, or covered by a
+    /// fullscreen window), so that it can stop animating hidden ones
+    Visibility(Visibility),
+    /// Start a playlist, replacing any other in its outputs. Setting an image or clearing the
+    /// outputs stops it
+    Playlist(Playlist),
+    /// Switch to the next image in the playlists of these outputs (or all of them, if empty)
+    Next(Box<[String]>),
+    /// Switch to the previous image in the playlists of these outputs
+    Prev(Box<[String]>),
+    /// Read the schedule file again
+    ReloadSchedule,
+    /// `swww init` finished restoring the cache, even if there was nothing to restore. Until
+    /// then, the daemon leaves the outputs alone, so that it doesn't fight over them with it
+    Restored,
 }
 
 impl Request {
@@ -230,49 +495,90 @@ impl Request {
             Err(e) => return Err(format!("Failed to serialize request: {e}")),
         };
 
-        std::thread::scope(|s| {
-            if let Self::Animation(animations) = self {
-                s.spawn(|| {
-                    for (animation, _) in animations.iter() {
-                        if let Err(e) = cache::store_animation_frames(animation) {
-                            eprintln!("Error storing cache for {}: {e}", animation.path);
-                        }
-                    }
-                });
-            }
-            let mut writer = BufWriter::new(stream);
-            if let Err(e) = writer.write_all(&bytes.len().to_ne_bytes()) {
-                return Err(format!("failed to write serialized request's length: {e}"));
-            }
-            if let Err(e) = writer.write_all(&bytes) {
-                Err(format!("failed to write serialized request: {e}"))
-            } else {
-                if let Self::Img((_, imgs)) = self {
-                    for (Img { path, .. }, outputs) in imgs.iter() {
-                        for output in outputs.iter() {
-                            if let Err(e) = super::cache::store(output, path) {
-                                eprintln!("ERROR: failed to store cache: {e}");
-                            }
-                        }
-                    }
-                }
-                Ok(())
+        let mut writer = BufWriter::new(stream);
+        if let Err(e) = writer.write_all(&bytes.len().to_ne_bytes()) {
+            return Err(format!("failed to write serialized request's length: {e}"));
+        }
+        writer
+            .write_all(&bytes)
+            .map_err(|e| format!("failed to write serialized request: {e}"))
+    }
+
+    /// Validates `bytes` before handing out the archived request, so that malformed messages
+    /// are rejected instead of being read as garbage
+    pub fn receive(bytes: &[u8]) -> Result<&ArchivedRequest, MessageError> {
+        rkyv::check_archived_root::<Self>(bytes)
+            .map_err(|e| MessageError::Invalid(format!("failed to validate request: {e}")))
+    }
+}
+
+/// A serialized `Request` that was validated when it was built. Validating walks the whole
+/// message, which can hold entire images, so we do it only once, and read the request through
+/// this afterwards, from whichever thread ends up handling it
+pub struct ValidRequest(AlignedVec);
+
+impl ValidRequest {
+    /// Validates `bytes` as an archived `Request`
+    pub fn new(bytes: AlignedVec) -> Result<Self, MessageError> {
+        Request::receive(&bytes)?;
+        Ok(Self(bytes))
+    }
+
+    pub fn get(&self) -> &ArchivedRequest {
+        // SAFETY: `new` is the only way to build a `ValidRequest`, and it made sure these bytes
+        // hold a valid archived `Request`. Nothing can modify them afterwards, since we never
+        // hand out mutable access to them
+        unsafe { rkyv::archived_root::<Request>(&self.0) }
+    }
+}
+
+/// Why the daemon refused to process a message
+#[derive(Debug, Archive, Serialize, Deserialize)]
+#[archive(check_bytes)]
+pub enum MessageError {
+    /// The length prefix is larger than the maximum message size we accept
+    TooLarge { len: u64, max: u64 },
+    /// We could not read the whole message from the socket
+    Unreadable(String),
+    /// The bytes we read are not a valid archived message
+    Invalid(String),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { len, max } => {
+                write!(
+                    f,
+                    "message of {len} bytes exceeds the maximum size of {max} bytes"
+                )
             }
-        })
+            Self::Unreadable(e) => write!(f, "failed to read message: {e}"),
+            Self::Invalid(e) => write!(f, "invalid message: {e}"),
+        }
     }
+}
 
-    #[must_use]
-    pub fn receive(bytes: &[u8]) -> &ArchivedRequest {
-        unsafe { rkyv::archived_root::<Self>(bytes) }
+impl fmt::Display for ArchivedMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let error: MessageError = self.deserialize(&mut rkyv::Infallible).unwrap();
+        error.fmt(f)
     }
 }
 
 #[derive(Archive, Serialize)]
+#[archive(check_bytes)]
 pub enum Answer {
     Ok,
     Err(String),
     Info(Box<[BgInfo]>),
     Ping(bool),
+    /// The daemon could not make sense of the request it received
+    Malformed(MessageError),
+    /// The daemon is exiting. It doesn't remove its socket when the socket belongs to systemd
+    Killed {
+        removes_socket: bool,
+    },
 }
 
 impl Answer {
@@ -292,40 +598,363 @@ impl Answer {
         }
     }
 
+    pub fn receive(bytes: &[u8]) -> Result<&ArchivedAnswer, String> {
+        rkyv::check_archived_root::<Self>(bytes)
+            .map_err(|e| format!("failed to validate answer from daemon: {e}"))
+    }
+}
+
+/// Something that happened in the daemon, streamed to clients that sent a `Request::Subscribe`
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub enum Event {
+    OutputAdded {
+        output: String,
+        dim: (u32, u32),
+        scale_factor: i32,
+    },
+    OutputRemoved {
+        output: String,
+    },
+    OutputResized {
+        output: String,
+        dim: (u32, u32),
+        scale_factor: i32,
+    },
+    TransitionFinished {
+        output: String,
+        img: BgImg,
+    },
+    AnimationStarted {
+        output: String,
+    },
+    AnimationStopped {
+        output: String,
+    },
+}
+
+impl Event {
+    /// Serializes the event with the same length prefix we use for answers
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let bytes = match rkyv::to_bytes::<_, 256>(self) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(format!("Failed to serialize event: {e}")),
+        };
+        let mut msg = Vec::with_capacity(std::mem::size_of::<usize>() + bytes.len());
+        msg.extend_from_slice(&bytes.len().to_ne_bytes());
+        msg.extend_from_slice(&bytes);
+        Ok(msg)
+    }
+
+    pub fn receive(bytes: &[u8]) -> Result<&ArchivedEvent, String> {
+        rkyv::check_archived_root::<Self>(bytes)
+            .map_err(|e| format!("failed to validate event from daemon: {e}"))
+    }
+}
+
+impl ArchivedEvent {
+    /// Serializes the event as a single line JSON object, as documented in `swww-watch(1)`
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        match self {
+            Self::OutputAdded {
+                output,
+                dim,
+                scale_factor,
+            } => format!(
+                r#"{{"event":"output_added","output":{},"width":{},"height":{},"scale_factor":{}}}"#,
+                json_string(output),
+                dim.0,
+                dim.1,
+                scale_factor
+            ),
+            Self::OutputRemoved { output } => format!(
+                r#"{{"event":"output_removed","output":{}}}"#,
+                json_string(output)
+            ),
+            Self::OutputResized {
+                output,
+                dim,
+                scale_factor,
+            } => format!(
+                r#"{{"event":"output_resized","output":{},"width":{},"height":{},"scale_factor":{}}}"#,
+                json_string(output),
+                dim.0,
+                dim.1,
+                scale_factor
+            ),
+            Self::TransitionFinished { output, img } => format!(
+                r#"{{"event":"transition_finished","output":{},"displaying":{}}}"#,
+                json_string(output),
+                img.to_json()
+            ),
+            Self::AnimationStarted { output } => format!(
+                r#"{{"event":"animation_started","output":{}}}"#,
+                json_string(output)
+            ),
+            Self::AnimationStopped { output } => format!(
+                r#"{{"event":"animation_stopped","output":{}}}"#,
+                json_string(output)
+            ),
+        }
+    }
+}
+
+/// Version of the wire protocol spoken over the socket.
+///
+/// This must be bumped every time the layout of `Request`, `Answer` or anything they contain
+/// changes, since reading rkyv bytes with a different layout is undefined behaviour
+pub const PROTOCOL_VERSION: u32 = 13;
+
+/// Every handshake starts with these, so we can tell a swww peer apart from anything else
+const HANDSHAKE_MAGIC: [u8; 4] = *b"swww";
+
+/// Optional features a peer supports, advertised during the handshake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// The daemon can stream [`Event`]s through `Request::Subscribe`
+    pub const SUBSCRIBE: Self = Self(1);
+
+    /// Everything this build supports
+    pub const SUPPORTED: Self = Self::SUBSCRIBE;
+
+    #[must_use]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[must_use]
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// The first exchange on every connection: both sides send one of these before anything else.
+///
+/// Its encoding is fixed and independent of rkyv, so that peers built from different versions
+/// can always read each other's handshake, and refuse to talk if their protocols differ
+#[derive(Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol: u32,
+    pub capabilities: Capabilities,
+    /// The crate version of the peer, purely for nicer error messages
+    pub version: String,
+}
+
+impl Handshake {
+    /// The handshake describing this build
+    #[must_use]
+    pub fn ours() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    pub fn send(&self, mut stream: &UnixStream) -> Result<(), String> {
+        let version = &self.version.as_bytes()[..self.version.len().min(u8::MAX as usize)];
+        let mut bytes = Vec::with_capacity(17 + version.len());
+        bytes.extend_from_slice(&HANDSHAKE_MAGIC);
+        bytes.extend_from_slice(&self.protocol.to_le_bytes());
+        bytes.extend_from_slice(&self.capabilities.0.to_le_bytes());
+        bytes.push(version.len() as u8);
+        bytes.extend_from_slice(version);
+        stream
+            .write_all(&bytes)
+            .map_err(|e| format!("failed to write handshake: {e}"))
+    }
+
+    pub fn receive(stream: &UnixStream) -> Result<Self, String> {
+        Self::receive_unless_closed(stream)?
+            .ok_or_else(|| "peer closed the connection before its handshake".to_string())
+    }
+
+    /// Like [`Handshake::receive`], but returns `None` if the peer closes the connection without
+    /// sending anything
+    fn receive_unless_closed(mut stream: &UnixStream) -> Result<Option<Self>, String> {
+        let mut header = [0; 17];
+        let read = stream
+            .read(&mut header[..1])
+            .map_err(|e| format!("failed to read handshake: {e}"))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        stream
+            .read_exact(&mut header[1..])
+            .map_err(|e| format!("failed to read handshake: {e}"))?;
+        if header[0..4] != HANDSHAKE_MAGIC {
+            return Err("peer did not start the connection with a swww handshake".to_string());
+        }
+        let protocol = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let capabilities = Capabilities(u64::from_le_bytes(header[8..16].try_into().unwrap()));
+        let mut version = vec![0; header[16] as usize];
+        stream
+            .read_exact(&mut version)
+            .map_err(|e| format!("failed to read handshake version: {e}"))?;
+
+        Ok(Some(Self {
+            protocol,
+            capabilities,
+            version: String::from_utf8_lossy(&version).to_string(),
+        }))
+    }
+
+    /// The capabilities shared by both peers, if they speak the same protocol
     #[must_use]
-    pub fn receive(bytes: &[u8]) -> &ArchivedAnswer {
-        unsafe { rkyv::archived_root::<Self>(bytes) }
+    pub fn shared_capabilities(&self, theirs: &Self) -> Option<Capabilities> {
+        if self.protocol == theirs.protocol {
+            Some(self.capabilities.intersection(theirs.capabilities))
+        } else {
+            None
+        }
     }
 }
 
-pub fn read_socket(stream: &UnixStream) -> Result<Vec<u8>, String> {
+/// Performs the client side of the handshake, returning the capabilities both sides share
+pub fn client_handshake(stream: &UnixStream) -> Result<Capabilities, String> {
+    let ours = Handshake::ours();
+    ours.send(stream)?;
+    let theirs = Handshake::receive(stream).map_err(|e| {
+        format!(
+            "client/daemon version mismatch: {e}. The daemon is probably from an older version \
+            of swww; restart it with `swww kill && swww init`"
+        )
+    })?;
+    ours.shared_capabilities(&theirs).ok_or_else(|| {
+        format!(
+            "client/daemon version mismatch: swww {} speaks protocol {}, but swww-daemon {} \
+            speaks protocol {}. Restart the daemon with `swww kill && swww init`",
+            ours.version, ours.protocol, theirs.version, theirs.protocol
+        )
+    })
+}
+
+/// Performs the daemon side of the handshake. Once we have read the client's handshake, we answer
+/// with our own, even if the versions differ, so the client can tell the user what went wrong.
+/// Peers that don't start with a valid handshake get no answer.
+///
+/// Returns `None` if the peer closed the connection without sending anything, which is how
+/// clients check whether a daemon is running
+pub fn daemon_handshake(stream: &UnixStream) -> Result<Option<Capabilities>, String> {
+    let theirs = match Handshake::receive_unless_closed(stream)? {
+        Some(theirs) => theirs,
+        None => return Ok(None),
+    };
+    let ours = Handshake::ours();
+    ours.send(stream)?;
+    ours.shared_capabilities(&theirs).map(Some).ok_or_else(|| {
+        format!(
+            "client/daemon version mismatch: swww {} speaks protocol {}, but we speak protocol {}",
+            theirs.version, theirs.protocol, ours.protocol
+        )
+    })
+}
+
+/// Default upper bound on the size of a single message, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 30;
+
+/// The largest message we are willing to read from the socket.
+///
+/// Defaults to [`DEFAULT_MAX_MESSAGE_SIZE`], and can be overridden with the
+/// `SWWW_MAX_MESSAGE_SIZE` environment variable (in bytes)
+#[must_use]
+pub fn max_message_size() -> usize {
+    std::env::var("SWWW_MAX_MESSAGE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+pub fn read_socket(stream: &UnixStream) -> Result<AlignedVec, String> {
+    read_message(stream, max_message_size()).map_err(|e| e.to_string())
+}
+
+/// Reads a length-prefixed message, refusing to allocate more than `max_len` bytes for it
+///
+/// The returned buffer is properly aligned for validating the archived message inside it
+pub fn read_message(stream: &UnixStream, max_len: usize) -> Result<AlignedVec, MessageError> {
     let mut reader = BufReader::new(stream);
-    let mut buf = vec![0; 8];
+    let mut len_buf = [0; std::mem::size_of::<usize>()];
 
     let mut tries = 0;
     loop {
-        match reader.read_exact(&mut buf[0..std::mem::size_of::<usize>()]) {
+        match reader.read_exact(&mut len_buf) {
             Ok(()) => break,
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::WouldBlock && tries < 5 {
                     std::thread::sleep(Duration::from_millis(1));
                 } else {
-                    return Err(format!("failed to read serialized length: {e}"));
+                    return Err(MessageError::Unreadable(format!(
+                        "failed to read serialized length: {e}"
+                    )));
                 }
             }
         }
         tries += 1;
     }
-    let len = usize::from_ne_bytes(buf[0..std::mem::size_of::<usize>()].try_into().unwrap());
-    buf.clear();
-    buf.resize(len, 0);
+    let len = usize::from_ne_bytes(len_buf);
+    if len > max_len {
+        return Err(MessageError::TooLarge {
+            len: len as u64,
+            max: max_len as u64,
+        });
+    }
 
-    if let Err(e) = reader.read_exact(&mut buf) {
-        return Err(format!("Failed to read request: {e}"));
+    let mut buf = AlignedVec::with_capacity(len);
+    buf.resize(len, 0);
+    if let Err(e) = reader.read_exact(buf.as_mut_slice()) {
+        return Err(MessageError::Unreadable(format!(
+            "failed to read {len} bytes of message: {e}"
+        )));
     }
     Ok(buf)
 }
 
+static NAMESPACE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Selects the daemon we are talking to (or, in the daemon, which one we are). Each namespace has
+/// its own socket and cache directory, so several daemons can run side by side.
+///
+/// This must be called before anything uses the socket or the cache, and only once. Not calling it
+/// at all selects the default daemon.
+pub fn set_namespace(namespace: Option<String>) -> Result<(), String> {
+    if let Some(namespace) = &namespace {
+        validate_namespace(namespace)?;
+    }
+    NAMESPACE
+        .set(namespace)
+        .map_err(|_| "the namespace was already set".to_string())
+}
+
+/// The namespace set with [`set_namespace`], if any
+#[must_use]
+pub fn namespace() -> Option<&'static str> {
+    NAMESPACE.get().and_then(|namespace| namespace.as_deref())
+}
+
+fn validate_namespace(namespace: &str) -> Result<(), String> {
+    if namespace.is_empty() {
+        return Err("the namespace cannot be empty".to_string());
+    }
+    if namespace.starts_with('.') || namespace.contains('/') || namespace.contains('\0') {
+        return Err(format!(
+            "invalid namespace {namespace:?}: it is used as a file name, so it cannot contain '/', or start with '.'"
+        ));
+    }
+    Ok(())
+}
+
+fn socket_name(namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("swww-{namespace}.socket"),
+        None => "swww.socket".to_string(),
+    }
+}
+
 #[must_use]
 pub fn get_socket_path() -> PathBuf {
     let runtime_dir = if let Ok(dir) = std::env::var("XDG_RUNTIME_DIR") {
@@ -334,7 +963,7 @@ pub fn get_socket_path() -> PathBuf {
         "/tmp/swww".to_string()
     };
     let runtime_dir = Path::new(&runtime_dir);
-    runtime_dir.join("swww.socket")
+    runtime_dir.join(socket_name(namespace()))
 }
 
 pub fn get_cache_path() -> Result<PathBuf, String> {
@@ -365,3 +994,143 @@ pub fn get_cache_path() -> Result<PathBuf, String> {
 
     Ok(cache_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_have_their_own_sockets() {
+        assert_eq!(socket_name(None), "swww.socket");
+        assert_eq!(socket_name(Some("nested")), "swww-nested.socket");
+        assert!(validate_namespace("nested-1").is_ok());
+        for invalid in ["", ".", "..", "a/b"] {
+            assert!(validate_namespace(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("DP-1"), r#""DP-1""#);
+        assert_eq!(
+            json_string("a \"quoted\"\\path\n"),
+            r#""a \"quoted\"\\path\n""#
+        );
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+
+    #[test]
+    fn bg_info_json() {
+        let info = BgInfo {
+            name: "eDP-1".to_string(),
+            dim: (1920, 1080),
+            position: (-1920, 0),
+            scale_factor: 2,
+            fractional_scale: None,
+            transform: Transform::Rotated90,
+            img: BgImg::Img("/tmp/a \"b\".png".to_string()),
+            animating: true,
+            transitioning: false,
+        };
+        let bytes = rkyv::to_bytes::<_, 256>(&info).unwrap();
+        let archived = rkyv::check_archived_root::<BgInfo>(&bytes).unwrap();
+        assert_eq!(
+            archived.to_json(),
+            r#"{"name":"eDP-1","width":1920,"height":1080,"x":-1920,"y":0,"scale_factor":2,"scale":2,"transform":"90","real_width":3840,"real_height":2160,"displaying":{"type":"image","path":"/tmp/a \"b\".png"},"animating":true,"transitioning":false}"#
+        );
+
+        let info = BgInfo {
+            img: BgImg::Color([0x20, 0x20, 0xff]),
+            // 1.25
+            fractional_scale: Some(150),
+            ..info
+        };
+        assert_eq!(info.real_dim(), (2400, 1350));
+        let bytes = rkyv::to_bytes::<_, 256>(&info).unwrap();
+        let archived = rkyv::check_archived_root::<BgInfo>(&bytes).unwrap();
+        assert!(archived
+            .to_json()
+            .contains(r#""displaying":{"type":"color","color":"2020ff"}"#));
+        assert!(archived.to_json().contains(r#""scale":1.25,"#));
+        assert_eq!(archived.real_dim(), (2400, 1350));
+    }
+
+    #[test]
+    fn event_roundtrip() {
+        let event = Event::TransitionFinished {
+            output: "HDMI-A-1".to_string(),
+            img: BgImg::Img("/tmp/wall.png".to_string()),
+        };
+        let (a, b) = UnixStream::pair().unwrap();
+        (&a).write_all(&event.to_bytes().unwrap()).unwrap();
+        let bytes = read_socket(&b).unwrap();
+        assert_eq!(
+            Event::receive(&bytes).unwrap().to_json(),
+            r#"{"event":"transition_finished","output":"HDMI-A-1","displaying":{"type":"image","path":"/tmp/wall.png"}}"#
+        );
+    }
+
+    #[test]
+    fn handshake_roundtrip() {
+        let (a, b) = UnixStream::pair().unwrap();
+        Handshake::ours().send(&a).unwrap();
+        assert_eq!(Handshake::receive(&b).unwrap(), Handshake::ours());
+    }
+
+    #[test]
+    fn handshake_succeeds_for_same_protocol() {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || daemon_handshake(&daemon));
+        assert_eq!(client_handshake(&client), Ok(Capabilities::SUPPORTED));
+        assert_eq!(daemon.join().unwrap(), Ok(Some(Capabilities::SUPPORTED)));
+    }
+
+    #[test]
+    fn handshake_ignores_peers_that_only_connect() {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        drop(client);
+        assert_eq!(daemon_handshake(&daemon), Ok(None));
+    }
+
+    #[test]
+    fn handshake_fails_for_different_protocol() {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        let daemon = std::thread::spawn(move || {
+            Handshake::receive(&daemon).unwrap();
+            Handshake {
+                protocol: PROTOCOL_VERSION + 1,
+                capabilities: Capabilities::NONE,
+                version: "9.9.9".to_string(),
+            }
+            .send(&daemon)
+            .unwrap();
+        });
+        let err = client_handshake(&client).unwrap_err();
+        assert!(err.starts_with("client/daemon version mismatch"), "{err}");
+        assert!(err.contains("9.9.9"), "{err}");
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_rejects_old_peers() {
+        // peers that predate the handshake start by sending the request's length
+        let (client, daemon) = UnixStream::pair().unwrap();
+        let mut bytes = 64usize.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 64]);
+        (&client).write_all(&bytes).unwrap();
+        assert!(daemon_handshake(&daemon).is_err());
+    }
+
+    #[test]
+    fn archived_message_errors_read_the_same() {
+        for error in [
+            MessageError::TooLarge { len: 10, max: 5 },
+            MessageError::Unreadable("eof".to_string()),
+            MessageError::Invalid("bad".to_string()),
+        ] {
+            let bytes = rkyv::to_bytes::<_, 64>(&error).unwrap();
+            let archived = rkyv::check_archived_root::<MessageError>(&bytes).unwrap();
+            assert_eq!(archived.to_string(), error.to_string());
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod cache;
 pub mod compression;
+pub mod imgproc;
 pub mod ipc;
+pub mod schedule;
@@ -0,0 +1,485 @@
+//! Schedule files, which tell the daemon which image to display at each time of the day
+//!
+//! A schedule is made of display options, followed by one entry per line:
+//!
+//! ```text
+//! # lines starting with '#' are comments
+//! latitude = 48.85
+//! longitude = 2.35
+//! transition-type = fade
+//!
+//! 07:00-12:00 mon-fri  morning.png
+//! sunrise-sunset       day.png
+//! sunset-sunrise       night.png
+//!
+//! [DP-1]
+//! 00:00-24:00 sat,sun  /home/user/weekend.png
+//! ```
+//!
+//! The options are the same as the ones in the cache (see [`CachedImg`]), plus the location used
+//! to compute sunrise and sunset. Entries in an `[output]` section only apply to that output, and
+//! take precedence over the ones before any section. Otherwise, the first matching entry wins.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::cache::CachedImg;
+
+/// Keys that may be set before the entries, besides the location
+const OPTION_KEYS: &[&str] = &[
+    "resize",
+    "filter",
+    "fill-color",
+    "transition-type",
+    "transition-step",
+    "transition-duration",
+    "transition-fps",
+    "transition-angle",
+    "transition-pos",
+    "transition-bezier",
+    "transition-wave",
+    "invert-y",
+];
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeOfDay {
+    /// Minutes since midnight
+    Clock(u32),
+    Sunrise,
+    Sunset,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sunrise" => Ok(Self::Sunrise),
+            "sunset" => Ok(Self::Sunset),
+            _ => {
+                let invalid = || format!("invalid time of day: {s} (expected HH:MM)");
+                let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+                let hours: u32 = hours.parse().map_err(|_| invalid())?;
+                let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+                // check the hours first, so that huge ones can't overflow
+                if hours > 24 || minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+                    return Err(invalid());
+                }
+                let time = hours * 60 + minutes;
+                Ok(Self::Clock(time))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The output this entry is restricted to, from the section it is in
+    pub output: Option<String>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// Days this entry applies to, where bit 0 is monday. Ranges that go past midnight belong to
+    /// the day they start in
+    pub days: u8,
+    pub path: String,
+}
+
+impl Entry {
+    fn contains(&self, now: &LocalTime, sun: (u32, u32)) -> bool {
+        let minutes = |time| match time {
+            TimeOfDay::Clock(minutes) => minutes,
+            TimeOfDay::Sunrise => sun.0,
+            TimeOfDay::Sunset => sun.1,
+        };
+        let (start, end) = (minutes(self.start), minutes(self.end));
+        let today = self.days & (1 << now.weekday) != 0;
+        let yesterday = self.days & (1 << ((now.weekday + 6) % 7)) != 0;
+        if start <= end {
+            today && start <= now.minute && now.minute < end
+        } else {
+            (today && now.minute >= start) || (yesterday && now.minute < end)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// How to display the images. Its path is meaningless
+    pub options: CachedImg,
+    /// Latitude and longitude, in degrees
+    pub location: Option<(f64, f64)>,
+    pub entries: Vec<Entry>,
+}
+
+impl Schedule {
+    /// Where the schedule is read from: `$SWWW_SCHEDULE`, or `$XDG_CONFIG_HOME/swww/schedule`
+    pub fn default_path() -> Result<PathBuf, String> {
+        if let Ok(path) = std::env::var("SWWW_SCHEDULE") {
+            Ok(path.into())
+        } else if let Ok(path) = std::env::var("XDG_CONFIG_HOME") {
+            Ok(PathBuf::from(path).join("swww/schedule"))
+        } else if let Ok(path) = std::env::var("HOME") {
+            Ok(PathBuf::from(path).join(".config/swww/schedule"))
+        } else {
+            Err("failed to read both $XDG_CONFIG_HOME and $HOME environment variables".to_string())
+        }
+    }
+
+    /// Reads the schedule in `path`. Relative image paths are relative to its directory
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read schedule {path:?}: {e}"))?;
+        let mut schedule: Self = contents
+            .parse()
+            .map_err(|e| format!("invalid schedule {path:?}: {e}"))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for entry in &mut schedule.entries {
+            entry.path = dir.join(&entry.path).to_string_lossy().to_string();
+        }
+        Ok(schedule)
+    }
+
+    /// The image `output` should display at `now`, if any
+    #[must_use]
+    pub fn image_for(&self, output: &str, now: &LocalTime) -> Option<&str> {
+        let sun = match self.location {
+            Some((latitude, longitude)) => {
+                sun_times(latitude, longitude, now.day_of_year, now.utc_offset)
+            }
+            // we only accept sunrise and sunset when we have a location
+            None => (0, 0),
+        };
+        let find = |output: Option<&str>| {
+            self.entries
+                .iter()
+                .find(|e| e.output.as_deref() == output && e.contains(now, sun))
+        };
+        find(Some(output))
+            .or_else(|| find(None))
+            .map(|e| e.path.as_str())
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = String::from("\n");
+        let (mut latitude, mut longitude) = (None, None);
+        let mut entries = Vec::new();
+        let mut section: Option<String> = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |e: String| format!("line {}: {e}", i + 1);
+
+            if let Some(output) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(output.trim().to_string());
+                continue;
+            }
+
+            let (range, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let is_entry =
+                range.starts_with(|c: char| c.is_ascii_digit()) || range.starts_with("sun");
+            if let Some((start, end)) = range.split_once('-').filter(|_| is_entry) {
+                let rest = rest.trim();
+                let (days, path) = match rest.split_once(char::is_whitespace) {
+                    Some((days, path)) => match parse_days(days) {
+                        Ok(days) => (days, path.trim()),
+                        Err(_) => (0b111_1111, rest),
+                    },
+                    None => (0b111_1111, rest),
+                };
+                if path.is_empty() || path == rest && parse_days(path).is_ok() {
+                    return Err(error("missing image path".to_string()));
+                }
+                entries.push(Entry {
+                    output: section.clone(),
+                    start: start.parse().map_err(error)?,
+                    end: end.parse().map_err(error)?,
+                    days,
+                    path: path.to_string(),
+                });
+            } else if let Some((key, value)) = line.split_once('=') {
+                if section.is_some() {
+                    return Err(error("options must come before any section".to_string()));
+                }
+                let (key, value) = (key.trim(), value.trim());
+                let parse_degrees = |value: &str| {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| error(format!("invalid {key}: {value}")))
+                };
+                match key {
+                    "latitude" => latitude = Some(parse_degrees(value)?),
+                    "longitude" => longitude = Some(parse_degrees(value)?),
+                    _ if OPTION_KEYS.contains(&key) => {
+                        options.push_str(&format!("{key}={value}\n"));
+                    }
+                    _ => return Err(error(format!("unknown option: {key}"))),
+                }
+            } else {
+                return Err(error(format!("expected an option or entry, found: {line}")));
+            }
+        }
+
+        let location = match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+            (None, None) => None,
+            _ => return Err("latitude and longitude must be set together".to_string()),
+        };
+        let uses_sun = entries.iter().any(|e| {
+            [e.start, e.end]
+                .iter()
+                .any(|t| matches!(t, TimeOfDay::Sunrise | TimeOfDay::Sunset))
+        });
+        if uses_sun && location.is_none() {
+            return Err("sunrise and sunset need a latitude and longitude".to_string());
+        }
+
+        Ok(Self {
+            options: options.parse()?,
+            location,
+            entries,
+        })
+    }
+}
+
+/// Parses a comma separated list of days or ranges of days, like `mon-fri,sun`
+fn parse_days(s: &str) -> Result<u8, String> {
+    const DAYS: [&str; 7] = [
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+    let day = |s: &str| {
+        let s = s.to_ascii_lowercase();
+        DAYS.iter()
+            .position(|day| s == *day || s == day[..3])
+            .ok_or_else(|| format!("invalid day: {s}"))
+    };
+    let mut days = 0;
+    for range in s.split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (day(start)?, day(end)?),
+            None => (day(range)?, day(range)?),
+        };
+        let mut i = start;
+        loop {
+            days |= 1 << i;
+            if i == end {
+                break;
+            }
+            i = (i + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// Sunrise and sunset at the given location, in minutes since local midnight, following NOAA's
+/// approximations. In polar days the sun "rises" at midnight and "sets" at the next one, and in
+/// polar nights the other way around, so that `sunrise-sunset` and `sunset-sunrise` cover the
+/// whole day between them
+#[must_use]
+pub fn sun_times(latitude: f64, longitude: f64, day_of_year: u32, utc_offset: i64) -> (u32, u32) {
+    use std::f64::consts::PI;
+
+    // fractional year, in radians, at noon
+    let g = 2.0 * PI / 365.0 * (day_of_year as f64 - 0.5);
+    let eqtime = 229.18
+        * (0.000075 + 0.001868 * g.cos()
+            - 0.032077 * g.sin()
+            - 0.014615 * (2.0 * g).cos()
+            - 0.040849 * (2.0 * g).sin());
+    let decl = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin() - 0.006758 * (2.0 * g).cos()
+        + 0.000907 * (2.0 * g).sin()
+        - 0.002697 * (3.0 * g).cos()
+        + 0.00148 * (3.0 * g).sin();
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle =
+        90.833f64.to_radians().cos() / (latitude.cos() * decl.cos()) - latitude.tan() * decl.tan();
+    if cos_hour_angle > 1.0 {
+        return (MINUTES_PER_DAY, 0);
+    } else if cos_hour_angle < -1.0 {
+        return (0, MINUTES_PER_DAY);
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+
+    let local = |utc_minutes: f64| {
+        let minutes = utc_minutes + utc_offset as f64 / 60.0;
+        minutes.rem_euclid(MINUTES_PER_DAY as f64) as u32
+    };
+    (
+        local(720.0 - 4.0 * (longitude + hour_angle) - eqtime),
+        local(720.0 - 4.0 * (longitude - hour_angle) - eqtime),
+    )
+}
+
+/// The parts of the local time schedules care about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 is monday
+    pub weekday: u8,
+    /// Minutes since midnight
+    pub minute: u32,
+    pub second: u32,
+    /// Starting from 1
+    pub day_of_year: u32,
+    /// Seconds ahead of UTC
+    pub utc_offset: i64,
+}
+
+impl LocalTime {
+    pub fn now() -> Result<Self, String> {
+        // SAFETY: `localtime_r` only writes to `tm`, which is a plain C struct that may be zeroed
+        let tm = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&now, &mut tm).is_null() {
+                return Err("failed to get the local time".to_string());
+            }
+            tm
+        };
+        Ok(Self {
+            weekday: ((tm.tm_wday + 6) % 7) as u8,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u32,
+            // leap seconds
+            second: tm.tm_sec.min(59) as u32,
+            day_of_year: tm.tm_yday as u32 + 1,
+            utc_offset: tm.tm_gmtoff,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE: &str = "
+        # comment
+        latitude = 48.85
+        longitude = 2.35
+        transition-type = fade
+        resize = fit
+        transition-pos = 0.5,-0.5
+
+        07:00-12:00 mon-fri  morning.png
+        22:00-06:00 fri      /late night.png
+        sunrise-sunset       /day.png
+        00:00-24:00          /night.png
+
+        [DP-1]
+        00:00-24:00 sat,sunday /weekend.png
+    ";
+
+    fn at(weekday: u8, hours: u32, minutes: u32) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: hours * 60 + minutes,
+            second: 0,
+            // the 21st of June
+            day_of_year: 172,
+            utc_offset: 2 * 3600,
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule: Schedule = SCHEDULE.parse().unwrap();
+        assert_eq!(schedule.location, Some((48.85, 2.35)));
+        assert_eq!(schedule.options.resize, crate::imgproc::ResizeStrategy::Fit);
+        assert!(schedule.options.transition.is_some());
+        assert_eq!(schedule.entries.len(), 5);
+        assert_eq!(schedule.entries[0].days, 0b001_1111);
+        assert_eq!(schedule.entries[1].path, "/late night.png");
+        assert_eq!(schedule.entries[4].output.as_deref(), Some("DP-1"));
+        assert_eq!(schedule.entries[4].days, 0b110_0000);
+    }
+
+    #[test]
+    fn finds_the_right_image() {
+        let schedule: Schedule = SCHEDULE.parse().unwrap();
+        let image = |output, now| schedule.image_for(output, &now);
+        assert_eq!(image("HDMI-A-1", at(0, 8, 0)), Some("morning.png"));
+        assert_eq!(image("HDMI-A-1", at(5, 8, 0)), Some("/day.png"));
+        assert_eq!(image("HDMI-A-1", at(0, 23, 0)), Some("/night.png"));
+        // ranges past midnight belong to the day they start in
+        assert_eq!(image("HDMI-A-1", at(4, 23, 0)), Some("/late night.png"));
+        assert_eq!(image("HDMI-A-1", at(5, 3, 0)), Some("/late night.png"));
+        assert_eq!(image("HDMI-A-1", at(4, 3, 0)), Some("/night.png"));
+        // sections take precedence
+        assert_eq!(image("DP-1", at(6, 8, 0)), Some("/weekend.png"));
+        assert_eq!(image("DP-1", at(0, 8, 0)), Some("morning.png"));
+    }
+
+    #[test]
+    fn computes_sun_times() {
+        // Paris, on the 21st of June: the sun rises at 05:47 and sets at 21:58
+        let (sunrise, sunset) = sun_times(48.85, 2.35, 172, 2 * 3600);
+        assert!(sunrise.abs_diff(5 * 60 + 47) <= 3, "sunrise: {sunrise}");
+        assert!(sunset.abs_diff(21 * 60 + 58) <= 3, "sunset: {sunset}");
+        // polar day and night
+        assert_eq!(sun_times(80.0, 0.0, 172, 0), (0, MINUTES_PER_DAY));
+        assert_eq!(sun_times(-80.0, 0.0, 172, 0), (MINUTES_PER_DAY, 0));
+    }
+
+    #[test]
+    fn polar_days_and_nights_are_entirely_day_or_night() {
+        for (latitude, expected) in [(80.0, "/day.png"), (-80.0, "/night.png")] {
+            let schedule: Schedule = format!(
+                "latitude = {latitude}\nlongitude = 0\n\
+                 sunrise-sunset /day.png\nsunset-sunrise /night.png"
+            )
+            .parse()
+            .unwrap();
+            for hours in 0..24 {
+                let now = at(0, hours, 30);
+                let sun = sun_times(latitude, 0.0, now.day_of_year, now.utc_offset);
+                let matching: Vec<_> = schedule
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.contains(&now, sun))
+                    .map(|entry| entry.path.as_str())
+                    .collect();
+                assert_eq!(matching, [expected], "latitude {latitude}, at {hours}:30");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!("sunrise-12:00 /a.png".parse::<Schedule>().is_err());
+        assert!("latitude = 10\nsunrise-12:00 /a.png"
+            .parse::<Schedule>()
+            .is_err());
+        assert!("07:00-25:00 /a.png".parse::<Schedule>().is_err());
+        assert!("71582789:00-12:00 /a.png".parse::<Schedule>().is_err());
+        assert!("07:00-4294967295:59 /a.png".parse::<Schedule>().is_err());
+        assert!("07:00-12:00".parse::<Schedule>().is_err());
+        assert!("07:00-12:00 mon".parse::<Schedule>().is_err());
+        assert!("transition-tpye = fade".parse::<Schedule>().is_err());
+        assert!("[DP-1]\nresize = fit".parse::<Schedule>().is_err());
+        assert!("resize = stretch".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(parse_days("mon"), Ok(0b000_0001));
+        assert_eq!(parse_days("Monday,sun"), Ok(0b100_0001));
+        assert_eq!(parse_days("fri-mon"), Ok(0b111_0001));
+        assert!(parse_days("someday").is_err());
+        assert!(parse_days("/a.png").is_err());
+    }
+}